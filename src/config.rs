@@ -0,0 +1,183 @@
+#![warn(clippy::pedantic)]
+#![warn(clippy::nursery)]
+#![warn(clippy::cargo)]
+#![allow(clippy::default_trait_access)]
+#![allow(clippy::multiple_crate_versions)]
+#![allow(clippy::redundant_pub_crate)]
+#![allow(clippy::uninlined_format_args)]
+#![allow(clippy::unnecessary_semicolon)]
+
+//! The user configuration file, `config.toml` in the msh configuration directory:
+//!
+//! ```toml
+//! registry = "~/work/repos.toml"   # preloaded when -r is not given
+//! prompt = "[{group}] {cwd}$ "     # {group}, {count} and {cwd} are replaced
+//! edit_mode = "vi"                 # or emacs
+//! color = "never"                  # or auto, always
+//!
+//! [settings]                       # anything accepted by `set`
+//! jobs = 4
+//! timeout = 300
+//! ```
+//!
+//! A relative `registry` path is resolved against the directory of the configuration file.
+
+use serde::Deserialize;
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::registry;
+
+/// Line editing key bindings for the REPL.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum EditMode {
+    #[default]
+    Emacs,
+    Vi,
+}
+
+/// When to color output.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum ColorMode {
+    /// Only when standard output is a terminal
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct UserConfig {
+    pub registry: Option<String>,
+    pub prompt: Option<String>,
+    #[serde(default)]
+    pub edit_mode: EditMode,
+    #[serde(default)]
+    pub color: ColorMode,
+    #[serde(default)]
+    settings: BTreeMap<String, toml::Value>,
+}
+
+impl UserConfig {
+    /// The registry file to preload, where a relative path is relative to the directory of
+    /// `config_file`, the file this configuration was read from.
+    pub fn registry_file(&self, config_file: Option<&Path>) -> Option<PathBuf> {
+        let base = config_file
+            .and_then(Path::parent)
+            .unwrap_or_else(|| Path::new(""));
+        self.registry.as_ref().map(|r| registry::resolve(base, r))
+    }
+
+    /// The settings to apply at startup, as `set` would receive them.
+    pub fn settings(&self) -> Vec<(String, String)> {
+        self.settings
+            .iter()
+            .map(|(name, value)| {
+                let value = match value {
+                    toml::Value::String(s) => s.clone(),
+                    other => other.to_string(),
+                };
+                (name.clone(), value)
+            })
+            .collect()
+    }
+}
+
+fn find_config_file() -> Option<PathBuf> {
+    dirs::config_dir().map(|mut d| {
+        d.push("msh");
+        d.push("config.toml");
+        d
+    })
+}
+
+/// The rc file of builtin commands run when the interactive shell starts.
+pub(crate) fn find_rc_file() -> Option<PathBuf> {
+    dirs::home_dir().map(|mut d| {
+        d.push(".mshrc");
+        d
+    })
+}
+
+/// Reads the configuration file at `path`, or at the default location, and returns it along
+/// with the path it was read from. Only a missing default file is allowed, giving the default
+/// configuration and no path.
+pub(crate) fn load(path: Option<&str>) -> Result<(UserConfig, Option<PathBuf>), String> {
+    let path = match path {
+        Some(p) => PathBuf::from(p),
+        None => match find_config_file() {
+            Some(p) if p.is_file() => p,
+            _ => {
+                info!("No configuration file found");
+                return Ok((UserConfig::default(), None));
+            }
+        },
+    };
+
+    debug!("Configuration file location: {}", path.display());
+    let contents = fs::read_to_string(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let user = toml::from_str(&contents).map_err(|e| format!("{}: {}", path.display(), e))?;
+    Ok((user, Some(path)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::context::get_home_dir;
+
+    fn registry(path: &str) -> UserConfig {
+        UserConfig {
+            registry: Some(path.to_owned()),
+            ..UserConfig::default()
+        }
+    }
+
+    #[test]
+    fn registry_is_relative_to_the_config_file() {
+        let file = Path::new("/etc/msh/config.toml");
+        assert_eq!(
+            registry("repos.toml").registry_file(Some(file)),
+            Some(PathBuf::from("/etc/msh/repos.toml"))
+        );
+        assert_eq!(
+            registry("../work/repos.toml").registry_file(Some(file)),
+            Some(PathBuf::from("/etc/msh/../work/repos.toml"))
+        );
+        assert_eq!(
+            registry("/srv/repos.toml").registry_file(Some(file)),
+            Some(PathBuf::from("/srv/repos.toml"))
+        );
+        assert_eq!(
+            registry("~/repos.toml").registry_file(Some(file)),
+            Some(Path::new(get_home_dir()).join("repos.toml"))
+        );
+        // A --config path given relative to the current directory
+        assert_eq!(
+            registry("repos.toml").registry_file(Some(Path::new("config.toml"))),
+            Some(PathBuf::from("repos.toml"))
+        );
+        assert_eq!(UserConfig::default().registry_file(Some(file)), None);
+    }
+
+    #[test]
+    fn load_returns_the_file_read() {
+        let dir = std::env::temp_dir().join(format!("msh-config-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("config.toml");
+        fs::write(&file, "registry = \"repos.toml\"\n").unwrap();
+
+        let (user, loaded) = load(file.to_str()).unwrap();
+        assert_eq!(loaded.as_deref(), Some(file.as_path()));
+        assert_eq!(
+            user.registry_file(loaded.as_deref()),
+            Some(dir.join("repos.toml"))
+        );
+        assert!(load(dir.join("missing.toml").to_str()).is_err());
+    }
+}
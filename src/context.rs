@@ -3,39 +3,135 @@
 #![warn(clippy::cargo)]
 #![allow(clippy::default_trait_access)]
 #![allow(clippy::multiple_crate_versions)]
-
-use colored::Colorize;
-
-use rayon::prelude::*;
+#![allow(clippy::redundant_pub_crate)]
+#![allow(clippy::uninlined_format_args)]
+#![allow(clippy::unnecessary_semicolon)]
 
 use std::borrow::ToOwned;
-use std::collections::HashSet;
+use std::collections::{BTreeMap, BTreeSet};
 use std::env;
-use std::fmt::{Display, Error as FmtError, Formatter};
-use std::fs::File;
-use std::io::prelude::*;
+use std::fmt::Write as FmtWrite;
 use std::mem;
-use std::path::PathBuf;
-use std::process::Command;
+use std::path::{Path, PathBuf};
 use std::string::ToString;
 
+use crate::config::{ColorMode, EditMode};
+use crate::discover::{self, Discovery};
+use crate::exec::{self, CommandList, Exclusion, Job, Outcome, Report, Target};
+use crate::registry::{self, RegistryEntry};
+use crate::selector::{self, Selector};
+use crate::settings::{Order, OutputMode, Settings};
+use crate::state::State;
+use crate::workspace;
+
 #[derive(Debug, Default, Clone, PartialEq, Hash, Builder)]
 #[builder(setter(into))]
 pub(crate) struct MshConfig {
     #[builder(default)]
-    preload_dirs: Vec<String>,
+    registry_file: Option<String>,
+    #[builder(default)]
+    settings: Vec<(String, String)>,
+    #[builder(default)]
+    fresh: bool,
+    #[builder(default)]
+    command: Option<String>,
+    #[builder(default)]
+    script: Option<String>,
+    #[builder(default)]
+    norc: bool,
+    #[builder(default)]
+    prompt: Option<String>,
+    #[builder(default)]
+    edit_mode: EditMode,
+    #[builder(default)]
+    color: ColorMode,
 }
 
 impl MshConfig {
-    pub const fn dirs(&self) -> &Vec<String> {
-        &self.preload_dirs
+    /// The registry file to preload, if one was given.
+    pub fn registry_file(&self) -> Option<&str> {
+        self.registry_file.as_deref()
+    }
+
+    pub const fn settings(&self) -> &Vec<(String, String)> {
+        &self.settings
+    }
+
+    /// Whether to skip restoring and saving the registry across sessions.
+    pub const fn fresh(&self) -> bool {
+        self.fresh
+    }
+
+    /// The commands given with `-c`, to run instead of starting the REPL.
+    pub fn command(&self) -> Option<&str> {
+        self.command.as_deref()
+    }
+
+    /// The script file to run instead of starting the REPL.
+    pub fn script(&self) -> Option<&str> {
+        self.script.as_deref()
+    }
+
+    /// Whether to skip running the rc file when the REPL starts.
+    pub const fn norc(&self) -> bool {
+        self.norc
+    }
+
+    /// The prompt format from the configuration file, if it set one.
+    pub fn prompt(&self) -> Option<&str> {
+        self.prompt.as_deref()
+    }
+
+    pub const fn edit_mode(&self) -> EditMode {
+        self.edit_mode
+    }
+
+    pub const fn color(&self) -> ColorMode {
+        self.color
     }
 }
 
-#[derive(Default, Debug, Clone, PartialEq)]
+/// The test deciding which directories the `filter` builtin keeps.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) enum Predicate {
+    /// The directory contains this file or directory
+    Exists(String),
+    /// This command exits successfully in the directory
+    Command(Vec<String>),
+}
+
+/// The group that always exists, and is active when msh starts.
+pub(crate) const DEFAULT_GROUP: &str = "default";
+
+/// Metadata attached to a registered directory, shared by every group it is in.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub(crate) struct DirInfo {
+    name: Option<String>,
+    tags: BTreeSet<String>,
+    env: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub(crate) struct Context {
     buffer: String,
-    dir_registry: HashSet<PathBuf>,
+    groups: BTreeMap<String, Vec<PathBuf>>,
+    active_group: String,
+    dir_info: BTreeMap<PathBuf, DirInfo>,
+    settings: Settings,
+}
+
+impl Default for Context {
+    fn default() -> Self {
+        let mut groups = BTreeMap::new();
+        groups.insert(DEFAULT_GROUP.to_owned(), Vec::new());
+        Self {
+            buffer: String::new(),
+            groups,
+            active_group: DEFAULT_GROUP.to_owned(),
+            dir_info: BTreeMap::new(),
+            settings: Settings::default(),
+        }
+    }
 }
 
 impl Context {
@@ -57,97 +153,535 @@ impl Context {
         full_line
     }
 
-    pub fn has_buffer(&self) -> bool {
+    pub const fn has_buffer(&self) -> bool {
         !self.buffer.is_empty()
     }
 
-    pub fn register(&mut self, path: &PathBuf) -> Result<(PathBuf, bool), String> {
+    /// The registry of the named group, or of the active group when `group` is `None`.
+    fn dir_registry(&self, group: Option<&str>) -> Result<&Vec<PathBuf>, String> {
+        let name = group.unwrap_or(&self.active_group);
+        self.groups
+            .get(name)
+            .ok_or_else(|| format!("No such group: {}", name))
+    }
+
+    fn dir_registry_mut(&mut self, group: Option<&str>) -> Result<&mut Vec<PathBuf>, String> {
+        let name = group.unwrap_or(&self.active_group);
+        self.groups
+            .get_mut(name)
+            .ok_or_else(|| format!("No such group: {}", name))
+    }
+
+    /// Registers `path` in `group`, creating the group if it does not exist yet.
+    pub fn register(
+        &mut self,
+        group: Option<&str>,
+        path: &Path,
+    ) -> Result<(PathBuf, bool), String> {
         let real_path = path.canonicalize().map_err(|e| e.to_string())?;
-        Ok((real_path.clone(), self.dir_registry.insert(real_path)))
+        if let Some(name) = group {
+            self.create_group(name)?;
+        }
+
+        let registry = self.dir_registry_mut(group)?;
+        if registry.contains(&real_path) {
+            return Ok((real_path, false));
+        }
+        registry.push(real_path.clone());
+        Ok((real_path, true))
     }
 
-    pub fn unregister(&mut self, path: &PathBuf) -> Result<(PathBuf, bool), String> {
+    pub fn unregister(
+        &mut self,
+        group: Option<&str>,
+        path: &Path,
+    ) -> Result<(PathBuf, bool), String> {
         let real_path = path.canonicalize().map_err(|e| e.to_string())?;
-        let was_there = self.dir_registry.remove(&real_path);
+        let registry = self.dir_registry_mut(group)?;
+        let before = registry.len();
+        registry.retain(|p| p != &real_path);
+        let was_there = registry.len() != before;
+        self.forget_unregistered(std::slice::from_ref(&real_path));
         Ok((real_path, was_there))
     }
 
-    pub fn clear_registry(&mut self) {
-        self.dir_registry.clear();
+    pub fn clear_registry(&mut self, group: Option<&str>) -> Result<(), String> {
+        let removed = mem::take(self.dir_registry_mut(group)?);
+        self.forget_unregistered(&removed);
+        Ok(())
+    }
+
+    /// Drops the names, tags and environment of any of `paths` no longer registered in any
+    /// group, so that they do not come back if the directory is registered again.
+    fn forget_unregistered(&mut self, paths: &[PathBuf]) {
+        for path in paths {
+            if !self.is_registered(path) && self.dir_info.remove(path).is_some() {
+                debug!("Forgetting details of {}", path.display());
+            }
+        }
     }
 
     pub fn dir_count(&self) -> usize {
-        self.dir_registry.len()
+        self.dir_registry(None).map_or(0, Vec::len)
     }
 
-    pub fn run_executable(&mut self, args: &[String]) {
-        assert!(!args.is_empty());
-        debug!("Execute command: {:?}", args);
-        if log_enabled!(log::Level::Trace) {
-            for arg in args.iter().by_ref() {
-                trace!("Arg found: \"{}\"", arg);
-            }
+    /// The directories registered in `group`, in the order selected by the `order` setting.
+    pub fn dirs(&self, group: Option<&str>) -> Result<Vec<PathBuf>, String> {
+        let mut dirs = self.dir_registry(group)?.clone();
+        if self.settings.order == Order::Path {
+            dirs.sort();
+        }
+        Ok(dirs)
+    }
+
+    /// Creates an empty group, returning false if it already existed.
+    pub fn create_group(&mut self, name: &str) -> Result<bool, String> {
+        selector::validate_name(name)?;
+        if self.groups.contains_key(name) {
+            return Ok(false);
+        }
+        debug!("Creating group: {}", name);
+        self.groups.insert(name.to_owned(), Vec::new());
+        Ok(true)
+    }
+
+    pub fn delete_group(&mut self, name: &str) -> Result<(), String> {
+        if name == DEFAULT_GROUP {
+            return Err(format!("The {} group cannot be deleted", DEFAULT_GROUP));
+        }
+        let removed = self
+            .groups
+            .remove(name)
+            .ok_or_else(|| format!("No such group: {}", name))?;
+        self.forget_unregistered(&removed);
+        if self.active_group == name {
+            DEFAULT_GROUP.clone_into(&mut self.active_group);
+        }
+        Ok(())
+    }
+
+    pub fn use_group(&mut self, name: &str) -> Result<(), String> {
+        if !self.groups.contains_key(name) {
+            return Err(format!(
+                "No such group: {} (create it with `group create {}`)",
+                name, name
+            ));
+        }
+        name.clone_into(&mut self.active_group);
+        Ok(())
+    }
+
+    pub fn active_group(&self) -> &str {
+        &self.active_group
+    }
+
+    /// Lists every group with its size, marking the active one.
+    pub fn describe_groups(&self) -> String {
+        let mut out = String::new();
+        for (name, dirs) in &self.groups {
+            let marker = if *name == self.active_group { "*" } else { " " };
+            let _ = writeln!(out, "{} {} ({})", marker, name, dirs.len());
+        }
+        out
+    }
 
-            for dir in &self.dir_registry {
-                trace!("Registered directory: {}", dir.display());
+    /// Lists the directories registered in `group`, or the active group.
+    pub fn describe(&self, group: Option<&str>) -> Result<String, String> {
+        let name = group.unwrap_or(&self.active_group);
+        let mut out = format!("Registered directories ({}):\n", name);
+        for path in self.dirs(group)? {
+            match self.dir_info.get(&path).and_then(|info| info.name.as_ref()) {
+                Some(dir_name) => writeln!(out, "{} ({})", path.display(), dir_name),
+                None => writeln!(out, "{}", path.display()),
             }
+            .ok();
+        }
+        Ok(out)
+    }
+
+    /// The directories registered in any group, or only in `group` when given, with their
+    /// groups, names, tags and environment, as written to a registry file.
+    pub fn entries(&self, group: Option<&str>) -> Result<Vec<RegistryEntry>, String> {
+        let paths = match group {
+            Some(name) => self.dirs(Some(name))?,
+            None => self.all_dirs(),
         };
+        let entries = paths
+            .into_iter()
+            .map(|path| {
+                let groups = self
+                    .groups
+                    .iter()
+                    .filter(|(name, dirs)| group.is_none_or(|g| g == *name) && dirs.contains(&path))
+                    .map(|(name, _)| name.clone())
+                    .collect();
+                let info = self.dir_info.get(&path).cloned().unwrap_or_default();
+                RegistryEntry {
+                    path,
+                    name: info.name,
+                    tags: info.tags.into_iter().collect(),
+                    groups,
+                    env: info.env,
+                    ..RegistryEntry::default()
+                }
+            })
+            .collect();
+        Ok(entries)
+    }
+
+    /// Every directory registered in any group: the active group's first, then the rest
+    /// by group name.
+    fn all_dirs(&self) -> Vec<PathBuf> {
+        let mut all = self.dirs(None).unwrap_or_default();
+        for (name, dirs) in &self.groups {
+            if *name == self.active_group {
+                continue;
+            }
+            for dir in dirs {
+                if !all.contains(dir) {
+                    all.push(dir.clone());
+                }
+            }
+        }
+        all
+    }
 
-        let empty = self.dir_registry.is_empty();
+    fn is_registered(&self, path: &Path) -> bool {
+        self.groups
+            .values()
+            .any(|dirs| dirs.iter().any(|d| d == path))
+    }
 
-        if empty {
-            let curdir = env::current_dir().expect("Current dir could not be read.");
-            debug!(
-                "no registered directories: executing against curdir: {}",
-                curdir.display()
-            );
-            self.dir_registry.insert(curdir);
+    /// Attaches `tag` to a registered directory, returning false if it already had it.
+    pub fn tag(&mut self, tag: &str, path: &Path) -> Result<(PathBuf, bool), String> {
+        selector::validate_name(tag)?;
+        let real_path = path.canonicalize().map_err(|e| e.to_string())?;
+        if !self.is_registered(&real_path) {
+            return Err("directory is not registered in any group".to_owned());
         }
 
-        self.dir_registry
-            .par_iter()
-            .map(|p| run_executable(args, p))
-            .for_each(drop);
+        let info = self.dir_info.entry(real_path.clone()).or_default();
+        let new = info.tags.insert(tag.to_owned());
+        Ok((real_path, new))
+    }
 
-        if empty {
-            self.dir_registry.clear();
+    pub fn untag(&mut self, tag: &str, path: &Path) -> Result<(PathBuf, bool), String> {
+        let real_path = path.canonicalize().map_err(|e| e.to_string())?;
+        let was_there = self
+            .dir_info
+            .get_mut(&real_path)
+            .is_some_and(|info| info.tags.remove(tag));
+        Ok((real_path, was_there))
+    }
+
+    /// Gives a registered directory a name, which selectors and output labels use in place
+    /// of its path.
+    pub fn name_dir(&mut self, name: &str, path: &Path) -> Result<(), String> {
+        selector::validate_name(name)?;
+        if !self.is_registered(path) {
+            return Err("directory is not registered in any group".to_owned());
+        }
+        if let Some((other, _)) = self
+            .dir_info
+            .iter()
+            .find(|(p, info)| *p != path && info.name.as_deref() == Some(name))
+        {
+            return Err(format!(
+                "name {} is already used by {}",
+                name,
+                other.display()
+            ));
         }
+
+        self.dir_info.entry(path.to_owned()).or_default().name = Some(name.to_owned());
+        Ok(())
     }
-}
 
-impl Display for Context {
-    fn fmt(&self, formatter: &mut Formatter) -> Result<(), FmtError> {
-        writeln!(formatter, "Registered directories:")?;
-        for path in &self.dir_registry {
-            writeln!(formatter, "{}", &path.display())?;
+    /// Adds environment variables to set whenever a command runs in a registered directory.
+    pub fn set_dir_env(
+        &mut self,
+        path: &Path,
+        env: &BTreeMap<String, String>,
+    ) -> Result<(), String> {
+        if !self.is_registered(path) {
+            return Err("directory is not registered in any group".to_owned());
         }
+
+        let info = self.dir_info.entry(path.to_owned()).or_default();
+        info.env
+            .extend(env.iter().map(|(k, v)| (k.clone(), v.clone())));
         Ok(())
     }
-}
 
-fn run_executable(args: &[String], path: &PathBuf) {
-    let raw_output = match Command::new(&args[0])
-        .args(args.iter().skip(1))
-        .current_dir(path)
-        .output()
-    {
-        Ok(o) => o,
-        Err(e) => {
-            eprintln!(
-                "Could not execute process on dir: {}, failed with error: {}",
-                path.display(),
-                e
+    fn has_tag(&self, path: &Path, tag: &str) -> bool {
+        self.dir_info
+            .get(path)
+            .is_some_and(|info| info.tags.contains(tag))
+    }
+
+    /// Lists every tag with the directories carrying it.
+    pub fn describe_tags(&self) -> String {
+        let mut tags: BTreeMap<&str, Vec<&Path>> = BTreeMap::new();
+        for (path, info) in &self.dir_info {
+            for tag in &info.tags {
+                tags.entry(tag).or_default().push(path);
+            }
+        }
+
+        let mut out = String::new();
+        for (tag, paths) in tags {
+            let _ = writeln!(out, "{}:", tag);
+            for path in paths {
+                let _ = writeln!(out, "  {}", path.display());
+            }
+        }
+        out
+    }
+
+    fn has_name(&self, path: &Path, name: &str) -> bool {
+        self.dir_info
+            .get(path)
+            .is_some_and(|info| info.name.as_deref() == Some(name))
+    }
+
+    /// The registered directories, from any group, matched by `selector`. A name in the
+    /// selector matches directories in the group of that name, carrying that tag, or given
+    /// that name.
+    pub fn select(&self, selector: &Selector) -> Result<Vec<PathBuf>, String> {
+        for name in selector.names() {
+            let is_known = self
+                .dir_info
+                .values()
+                .any(|info| info.tags.contains(name) || info.name.as_deref() == Some(name));
+            if !self.groups.contains_key(name) && !is_known {
+                return Err(format!("Unknown group, tag or directory name: {}", name));
+            }
+        }
+
+        let selected = self
+            .all_dirs()
+            .into_iter()
+            .filter(|path| {
+                selector.matches(&|name: &str| {
+                    let in_group = self
+                        .groups
+                        .get(name)
+                        .is_some_and(|dirs| dirs.contains(path));
+                    in_group || self.has_tag(path, name) || self.has_name(path, name)
+                })
+            })
+            .collect();
+        Ok(selected)
+    }
+
+    /// Captures the groups and directory metadata, to be saved for a later session.
+    pub fn snapshot(&self) -> State {
+        let mut state = State {
+            active_group: self.active_group.clone(),
+            groups: self.groups.clone(),
+            ..State::default()
+        };
+        for (path, info) in &self.dir_info {
+            for tag in &info.tags {
+                state
+                    .tags
+                    .entry(tag.clone())
+                    .or_default()
+                    .push(path.clone());
+            }
+            if let Some(name) = &info.name {
+                state.names.insert(name.clone(), path.clone());
+            }
+            if !info.env.is_empty() {
+                let key = path.to_string_lossy().into_owned();
+                state.env.insert(key, info.env.clone());
+            }
+        }
+        state
+    }
+
+    /// Replaces the groups and directory metadata with those from a saved session, dropping
+    /// any directories which no longer exist.
+    pub fn restore(&mut self, state: State) {
+        let exists = |path: &PathBuf| {
+            let found = path.is_dir();
+            if !found {
+                eprintln!(
+                    "Skipping missing directory from saved registry: {}",
+                    path.display()
+                );
+            }
+            found
+        };
+
+        self.groups = state
+            .groups
+            .into_iter()
+            .map(|(name, dirs)| (name, dirs.into_iter().filter(exists).collect()))
+            .collect();
+        self.groups.entry(DEFAULT_GROUP.to_owned()).or_default();
+
+        self.active_group = if self.groups.contains_key(&state.active_group) {
+            state.active_group
+        } else {
+            DEFAULT_GROUP.to_owned()
+        };
+
+        self.dir_info.clear();
+        for (tag, paths) in state.tags {
+            for path in paths {
+                if self.is_registered(&path) {
+                    let info = self.dir_info.entry(path).or_default();
+                    info.tags.insert(tag.clone());
+                }
+            }
+        }
+        for (name, path) in state.names {
+            if self.is_registered(&path) {
+                self.dir_info.entry(path).or_default().name = Some(name);
+            }
+        }
+        for (path, env) in state.env {
+            let path = PathBuf::from(path);
+            if self.is_registered(&path) {
+                self.dir_info.entry(path).or_default().env = env;
+            }
+        }
+    }
+
+    pub const fn settings(&self) -> &Settings {
+        &self.settings
+    }
+
+    pub fn set(&mut self, name: &str, value: &str) -> Result<(), String> {
+        self.settings.set(name, value)
+    }
+
+    /// Builds a labelled target for each path, with the name and environment it was given.
+    fn targets(&self, paths: Vec<PathBuf>) -> Vec<Target> {
+        let mut targets: Vec<Target> = paths
+            .into_iter()
+            .map(|path| {
+                let mut target = Target::new(path);
+                if let Some(info) = self.dir_info.get(&target.path) {
+                    target.name.clone_from(&info.name);
+                    target.env.clone_from(&info.env);
+                }
+                target
+            })
+            .collect();
+        exec::label_targets(&mut targets);
+        targets
+    }
+
+    /// Splits the directories of `group` into those which pass `predicate` and those which
+    /// fail it. Commands run quietly, through the executor.
+    pub fn partition(
+        &self,
+        group: Option<&str>,
+        predicate: &Predicate,
+    ) -> Result<(Vec<PathBuf>, Vec<PathBuf>), String> {
+        let paths = self.dirs(group)?;
+        let list = match predicate {
+            Predicate::Exists(file) => {
+                return Ok(paths.into_iter().partition(|p| p.join(file).exists()));
+            }
+            Predicate::Command(args) => CommandList::single(args.clone()),
+        };
+
+        let mut settings = self.settings.clone();
+        settings.output = OutputMode::Quiet;
+        let results = exec::run_all(&list, &self.targets(paths), &settings)?;
+
+        let mut passed = Vec::new();
+        let mut failed = Vec::new();
+        for result in results {
+            match result.outcome {
+                Outcome::Interrupted => return Err("Filter interrupted".to_owned()),
+                Outcome::SpawnFailed(e) | Outcome::Invalid(e) => {
+                    return Err(format!("Cannot run predicate: {}", e));
+                }
+                outcome if outcome.success() => passed.push(result.path),
+                _ => failed.push(result.path),
+            };
+        }
+        Ok((passed, failed))
+    }
+
+    /// The directories left out of a run by its exclusions.
+    fn excluded(&self, exclusions: &[Exclusion]) -> Result<Vec<PathBuf>, String> {
+        let mut excluded = Vec::new();
+        for exclusion in exclusions {
+            let dirs = match exclusion {
+                Exclusion::Path(path) => resolve_dirs(std::slice::from_ref(path)),
+                Exclusion::Name(name) => self.select(&Selector::Name(name.clone())),
+            };
+            excluded.extend(dirs.map_err(|e| format!("Cannot exclude: {}", e))?);
+        }
+        Ok(excluded)
+    }
+
+    /// The directories a job runs in: those it names or selects, or else every registered
+    /// directory, or else the current directory, less any it excludes.
+    fn target_paths(&self, job: &Job) -> Result<Vec<PathBuf>, String> {
+        let mut paths = match &job.selector {
+            _ if !job.paths.is_empty() => resolve_dirs(&job.paths)?,
+            Some(selector) => self.select(selector)?,
+            None => self.dirs(None)?,
+        };
+        if paths.is_empty() {
+            if job.selector.is_some() {
+                return Err("Selector matched no registered directories".to_owned());
+            }
+
+            let curdir = env::current_dir().expect("Current dir could not be read.");
+            debug!(
+                "no registered directories: executing against curdir: {}",
+                curdir.display()
             );
-            return;
+            paths.push(curdir);
         }
-    };
 
-    let output = String::from_utf8_lossy(&raw_output.stdout);
-    if !output.trim().is_empty() {
-        println!("{}:\n{}", &path.to_str().unwrap().cyan().bold().to_string(), output)
-    };
+        if !job.excludes.is_empty() {
+            let excluded = self.excluded(&job.excludes)?;
+            paths.retain(|p| !excluded.contains(p));
+            if paths.is_empty() {
+                return Err("Every target directory was excluded".to_owned());
+            }
+        }
+        Ok(paths)
+    }
+
+    pub fn run_executable(&self, job: &Job) -> Result<Report, String> {
+        let list = &job.list;
+        debug!("Execute command list: {:?}", list);
+        if log_enabled!(log::Level::Trace) {
+            for arg in list
+                .pipelines()
+                .flat_map(|p| &p.commands)
+                .flat_map(|c| &c.args)
+            {
+                trace!("Arg found: \"{}\"", arg.text());
+            }
+        };
+
+        let settings = self.settings.with_overrides(&job.overrides)?;
+
+        let paths = self.target_paths(job)?;
+
+        if log_enabled!(log::Level::Trace) {
+            for dir in &paths {
+                trace!("Target directory: {}", dir.display());
+            }
+        };
+
+        let results = exec::run_all(list, &self.targets(paths), &settings)?;
+
+        Ok(Report::new(results))
+    }
 }
 
+#[allow(clippy::non_std_lazy_statics)]
 pub(crate) fn get_home_dir() -> &'static str {
     lazy_static::lazy_static! {
         static ref HOME: String = dirs::home_dir()
@@ -160,29 +694,116 @@ pub(crate) fn get_home_dir() -> &'static str {
     &HOME
 }
 
-fn get_boxed_file(name: &str) -> Result<Box<dyn Read>, String> {
-    Ok(Box::new(File::open(name).map_err(|e| e.to_string())?))
+/// Resolves directories given on the command line, expanding any glob patterns, without
+/// registering them.
+fn resolve_dirs(paths: &[String]) -> Result<Vec<PathBuf>, String> {
+    let mut dirs = Vec::new();
+    for path in paths {
+        let found = if discover::is_pattern(path) {
+            discover::expand_pattern(path).map_err(|e| format!("{}: {}", path, e))?
+        } else {
+            vec![PathBuf::from(path)]
+        };
+
+        for dir in found {
+            let real_path = dir
+                .canonicalize()
+                .map_err(|e| format!("{}: {}", dir.display(), e))?;
+            if !real_path.is_dir() {
+                return Err(format!("{}: not a directory", dir.display()));
+            }
+            if !dirs.contains(&real_path) {
+                dirs.push(real_path);
+            }
+        }
+    }
+    Ok(dirs)
 }
 
-pub(crate) fn read_registry_file(name: &str) -> Result<Vec<String>, String> {
-    debug!("Searching for registry file: {}", name);
-    let mut reader = get_boxed_file(name)?;
-    trace!("File reader initialized");
+/// Registers every directory listed in a registry file, along with its metadata. Entries
+/// without groups of their own go into `group`, or the active group.
+pub(crate) fn register_file(ctx: &mut Context, group: Option<&str>, file: &str) {
+    let entries = match registry::read_registry_file(file) {
+        Ok(v) => v,
+        Err(e) => {
+            eprintln!("Cannot read registry file {}", e);
+            return;
+        }
+    };
+
+    for entry in entries {
+        if let Err(e) = register_entry(ctx, group, &entry) {
+            eprintln!(
+                "{}:{}: cannot register path {}: {}",
+                file,
+                entry.line,
+                entry.path.display(),
+                e
+            );
+        }
+    }
+}
 
-    let mut buf = String::new();
-    reader.read_to_string(&mut buf).map_err(|e| e.to_string())?;
+fn register_entry(
+    ctx: &mut Context,
+    group: Option<&str>,
+    entry: &RegistryEntry,
+) -> Result<(), String> {
+    let groups: Vec<Option<&str>> = if entry.groups.is_empty() {
+        vec![group]
+    } else {
+        entry.groups.iter().map(|g| Some(g.as_str())).collect()
+    };
 
-    trace!("File read, raw contents: {}", &buf);
+    let mut real_path = PathBuf::new();
+    for group in groups {
+        let (path, new) = ctx.register(group, &entry.path)?;
+        if new {
+            println!("Registered new path: {}", path.display());
+        } else {
+            println!("Already Registered: {}", path.display());
+        };
+        real_path = path;
+    }
 
-    Ok(buf.split_whitespace().map(ToOwned::to_owned).collect())
+    if let Some(name) = &entry.name {
+        ctx.name_dir(name, &real_path)?;
+    }
+    for tag in &entry.tags {
+        ctx.tag(tag, &real_path)?;
+    }
+    ctx.set_dir_env(&real_path, &entry.env)
 }
 
-pub(crate) fn register_paths(ctx: &mut Context, paths: &[String]) {
+/// Writes the directories of every group, or only of `group`, to a registry file.
+pub(crate) fn save_registry_file(
+    ctx: &Context,
+    group: Option<&str>,
+    file: &str,
+) -> Result<(), String> {
+    let entries = ctx.entries(group)?;
+    registry::write_registry_file(file, &entries)
+}
+
+pub(crate) fn register_paths(ctx: &mut Context, group: Option<&str>, paths: &[String]) {
     for path in paths {
-        let (real_path, new) = match ctx.register(&PathBuf::from(&path)) {
+        if discover::is_pattern(path) {
+            match discover::expand_pattern(path) {
+                Ok(dirs) => register_dirs(ctx, group, &dirs),
+                Err(e) => eprintln!("Cannot register pattern {}: {}", path, e),
+            };
+        } else {
+            register_dirs(ctx, group, &[PathBuf::from(path)]);
+        }
+    }
+}
+
+fn register_dirs(ctx: &mut Context, group: Option<&str>, paths: &[PathBuf]) {
+    for path in paths {
+        let (real_path, new) = match ctx.register(group, path) {
             Ok(x) => x,
             Err(e) => {
-                eprintln!("Cannot register path {}: {}", path, e);
+                eprintln!("Cannot register path {}: {}", path.display(), e);
                 continue;
             }
         };
@@ -195,9 +816,122 @@ pub(crate) fn register_paths(ctx: &mut Context, paths: &[String]) {
     }
 }
 
-pub(crate) fn unregister_paths(ctx: &mut Context, paths: &[String]) {
+/// Walks each of `roots` and registers every directory found by `discovery`.
+pub(crate) fn discover_paths(
+    ctx: &mut Context,
+    group: Option<&str>,
+    roots: &[String],
+    discovery: &Discovery,
+) {
+    for root in roots {
+        let root = Path::new(root);
+        if !root.is_dir() {
+            eprintln!("Cannot discover in {}: not a directory", root.display());
+            continue;
+        }
+
+        let found = match discovery.find(root) {
+            Ok(found) => found,
+            Err(e) => {
+                eprintln!("Cannot discover in {}: {}", root.display(), e);
+                return;
+            }
+        };
+        if found.is_empty() {
+            println!("No directories found in {}", root.display());
+        }
+        register_dirs(ctx, group, &found);
+    }
+}
+
+/// Keeps only the directories of `group` which pass `predicate`, or which fail it when
+/// `invert` is set. A dry run only prints what would be kept.
+pub(crate) fn filter_registry(
+    ctx: &mut Context,
+    group: Option<&str>,
+    predicate: &Predicate,
+    invert: bool,
+    dry_run: bool,
+) -> Result<(), String> {
+    let (passed, failed) = ctx.partition(group, predicate)?;
+    let (keep, drop) = if invert {
+        (failed, passed)
+    } else {
+        (passed, failed)
+    };
+
+    for path in &keep {
+        println!("Keeping: {}", path.display());
+    }
+    if dry_run {
+        for path in &drop {
+            println!("Would remove: {}", path.display());
+        }
+        return Ok(());
+    }
+    for path in &drop {
+        ctx.unregister(group, path)?;
+        println!("Removed path from registry: {}", path.display());
+    }
+    Ok(())
+}
+
+/// Registers every member of the Cargo or npm workspace at `root`, tagging each with the
+/// workspace name when `tag` is set.
+pub(crate) fn register_workspace(
+    ctx: &mut Context,
+    group: Option<&str>,
+    root: &str,
+    tag: bool,
+) -> Result<(), String> {
+    let workspace = workspace::read_workspace(Path::new(root))
+        .map_err(|e| format!("Cannot read workspace {}: {}", root, e))?;
+    if tag {
+        selector::validate_name(&workspace.name)
+            .map_err(|e| format!("Cannot tag with the workspace name: {}", e))?;
+    }
+    if workspace.members.is_empty() {
+        println!("No members found in workspace {}", workspace.name);
+    }
+
+    register_dirs(ctx, group, &workspace.members);
+    if tag {
+        for member in &workspace.members {
+            match ctx.tag(&workspace.name, member) {
+                Ok((real_path, true)) => {
+                    println!("Tagged {}: {}", workspace.name, real_path.display());
+                }
+                Ok(_) => {}
+                Err(e) => eprintln!("Cannot tag path {}: {}", member.display(), e),
+            };
+        }
+    }
+    Ok(())
+}
+
+pub(crate) fn tag_paths(ctx: &mut Context, tag: &str, paths: &[String]) {
+    for path in paths {
+        match ctx.tag(tag, &PathBuf::from(&path)) {
+            Ok((real_path, true)) => println!("Tagged {}: {}", tag, real_path.display()),
+            Ok((real_path, false)) => println!("Already tagged {}: {}", tag, real_path.display()),
+            Err(e) => eprintln!("Cannot tag path {}: {}", path, e),
+        };
+    }
+}
+
+pub(crate) fn untag_paths(ctx: &mut Context, tag: &str, paths: &[String]) {
     for path in paths {
-        let (real_path, new) = match ctx.unregister(&PathBuf::from(&path)) {
+        match ctx.untag(tag, &PathBuf::from(&path)) {
+            Ok((real_path, true)) => println!("Untagged {}: {}", tag, real_path.display()),
+            Ok((real_path, false)) => println!("Not tagged {}: {}", tag, real_path.display()),
+            Err(e) => eprintln!("Cannot untag path {}: {}", path, e),
+        };
+    }
+}
+
+pub(crate) fn unregister_paths(ctx: &mut Context, group: Option<&str>, paths: &[String]) {
+    for path in paths {
+        let (real_path, new) = match ctx.unregister(group, &PathBuf::from(&path)) {
             Ok(x) => x,
             Err(e) => {
                 eprintln!("Cannot unregister path {}: {}", path, e);
@@ -215,3 +949,137 @@ pub(crate) fn unregister_paths(ctx: &mut Context, paths: &[String]) {
         };
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+
+    /// A fresh directory for one test, holding an empty directory for each of `dirs`.
+    fn temp_dirs(name: &str, dirs: &[&str]) -> PathBuf {
+        let root = env::temp_dir().join(format!("msh-context-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        for dir in dirs {
+            fs::create_dir_all(root.join(dir)).unwrap();
+        }
+        root.canonicalize().unwrap()
+    }
+
+    fn job(excludes: &[&str]) -> Job {
+        Job {
+            overrides: Vec::new(),
+            selector: None,
+            paths: Vec::new(),
+            excludes: excludes.iter().map(|e| Exclusion::parse(e)).collect(),
+            list: CommandList::single(vec!["true".to_owned()]),
+        }
+    }
+
+    #[test]
+    fn exclusions_name_paths_tags_and_groups() {
+        let root = temp_dirs("excluded", &["api", "web", "old"]);
+        let mut ctx = Context::default();
+        for dir in ["api", "web", "old"] {
+            ctx.register(None, &root.join(dir)).unwrap();
+        }
+        ctx.register(Some("front"), &root.join("web")).unwrap();
+        ctx.tag("legacy", &root.join("old")).unwrap();
+
+        let excluded = |exclusions: &[&str]| {
+            ctx.excluded(
+                &exclusions
+                    .iter()
+                    .map(|e| Exclusion::parse(e))
+                    .collect::<Vec<_>>(),
+            )
+        };
+        assert_eq!(excluded(&["legacy"]).unwrap(), [root.join("old")]);
+        assert_eq!(excluded(&["front"]).unwrap(), [root.join("web")]);
+        let path = format!("{}/a*", root.display());
+        assert_eq!(excluded(&[path.as_str()]).unwrap(), [root.join("api")]);
+        assert!(excluded(&["unknown"]).is_err());
+
+        assert_eq!(
+            ctx.target_paths(&job(&["legacy", "front"])).unwrap(),
+            [root.join("api")]
+        );
+        assert_eq!(
+            ctx.target_paths(&job(&["legacy", "front", &path]))
+                .unwrap_err(),
+            "Every target directory was excluded"
+        );
+    }
+
+    #[test]
+    fn exclusions_apply_to_the_current_directory() {
+        let ctx = Context::default();
+        assert_eq!(
+            ctx.target_paths(&job(&["."])).unwrap_err(),
+            "Every target directory was excluded"
+        );
+        assert_eq!(
+            ctx.target_paths(&job(&[])).unwrap(),
+            [env::current_dir().unwrap()]
+        );
+    }
+
+    #[test]
+    fn directories_keep_registration_order_unless_sorted() {
+        let root = temp_dirs("order", &["b", "a", "c"]);
+        let mut ctx = Context::default();
+        for dir in ["b", "a", "c"] {
+            ctx.register(None, &root.join(dir)).unwrap();
+        }
+        let names = |ctx: &Context| {
+            ctx.dirs(None)
+                .unwrap()
+                .iter()
+                .map(|p| p.file_name().unwrap().to_string_lossy().into_owned())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(names(&ctx), ["b", "a", "c"]);
+
+        ctx.set("order", "path").unwrap();
+        assert_eq!(names(&ctx), ["a", "b", "c"]);
+    }
+
+    #[test]
+    fn snapshots_restore_groups_names_tags_and_env() {
+        let root = temp_dirs("snapshot", &["api", "web", "gone"]);
+        let (api, web, gone) = (root.join("api"), root.join("web"), root.join("gone"));
+        let mut ctx = Context::default();
+        ctx.register(None, &api).unwrap();
+        ctx.register(Some("front"), &web).unwrap();
+        ctx.register(Some("front"), &gone).unwrap();
+        ctx.use_group("front").unwrap();
+        ctx.tag("rust", &api).unwrap();
+        ctx.tag("old", &gone).unwrap();
+        ctx.name_dir("site", &web).unwrap();
+        let env = BTreeMap::from([("RUST_LOG".to_owned(), "debug".to_owned())]);
+        ctx.set_dir_env(&api, &env).unwrap();
+
+        // As saved to and read back from the state file
+        let saved = toml::to_string(&ctx.snapshot()).unwrap();
+        let state: State = toml::from_str(&saved).unwrap();
+        assert_eq!(state, ctx.snapshot());
+
+        let mut restored = Context::default();
+        restored.restore(state.clone());
+        assert_eq!(restored.snapshot(), state);
+        assert_eq!(restored.active_group(), "front");
+        assert_eq!(
+            restored.select(&Selector::Name("site".to_owned())).unwrap(),
+            std::slice::from_ref(&web)
+        );
+        let targets = restored.targets(vec![api]);
+        assert_eq!(targets[0].env, env);
+
+        // Directories removed since are dropped, along with their tags
+        fs::remove_dir(&gone).unwrap();
+        let mut restored = Context::default();
+        restored.restore(state);
+        assert_eq!(restored.dirs(Some("front")).unwrap(), [web]);
+        assert!(restored.select(&Selector::Name("old".to_owned())).is_err());
+    }
+}
@@ -0,0 +1,135 @@
+#![warn(clippy::pedantic)]
+#![warn(clippy::nursery)]
+#![warn(clippy::cargo)]
+#![allow(clippy::default_trait_access)]
+#![allow(clippy::multiple_crate_versions)]
+#![allow(clippy::redundant_pub_crate)]
+#![allow(clippy::uninlined_format_args)]
+#![allow(clippy::unnecessary_semicolon)]
+
+use glob::Pattern;
+use walkdir::{DirEntry, WalkDir};
+
+use std::path::{Path, PathBuf};
+
+use crate::exec;
+
+/// The markers looked for by `discover` when none are given.
+pub(crate) const DEFAULT_MARKERS: &[&str] = &[".git", "Cargo.toml", "package.json"];
+
+/// Whether `path` holds any glob metacharacters, and so should be expanded.
+pub(crate) fn is_pattern(path: &str) -> bool {
+    path.contains(['*', '?', '['])
+}
+
+/// The directories matched by a glob pattern, in path order.
+pub(crate) fn expand_pattern(pattern: &str) -> Result<Vec<PathBuf>, String> {
+    let paths = glob::glob(pattern).map_err(|e| format!("invalid pattern: {}", e))?;
+    let dirs: Vec<PathBuf> = paths
+        .take_while(|_| !exec::interrupted())
+        .filter_map(|entry| match entry {
+            Ok(path) => Some(path),
+            Err(e) => {
+                warn!("Skipping unreadable path while globbing: {}", e);
+                None
+            }
+        })
+        .filter(|path| path.is_dir())
+        .collect();
+
+    if exec::interrupted() {
+        return Err("interrupted".to_owned());
+    }
+    if dirs.is_empty() {
+        return Err("no directories match".to_owned());
+    }
+    Ok(dirs)
+}
+
+/// Which directories `discover` registers while walking a tree.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Discovery {
+    /// Files or directories, any one of which marks a directory to register
+    pub markers: Vec<String>,
+    /// How many levels below each root to search, or no limit
+    pub depth: Option<usize>,
+    /// Directories not to search, matched against their name or their path below the root
+    pub ignore: Vec<Pattern>,
+}
+
+impl Discovery {
+    pub fn new(
+        markers: Vec<String>,
+        depth: Option<usize>,
+        ignore: &[String],
+    ) -> Result<Self, String> {
+        let markers = if markers.is_empty() {
+            DEFAULT_MARKERS.iter().map(|&m| m.to_owned()).collect()
+        } else {
+            markers
+        };
+        let ignore = ignore
+            .iter()
+            .map(|p| Pattern::new(p).map_err(|e| format!("invalid ignore pattern {}: {}", p, e)))
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self {
+            markers,
+            depth,
+            ignore,
+        })
+    }
+
+    fn is_ignored(&self, root: &Path, entry: &DirEntry) -> bool {
+        if entry.depth() == 0 {
+            return false;
+        }
+
+        // Hidden directories, such as .git itself, are never searched
+        let name = entry.file_name().to_string_lossy();
+        if name.starts_with('.') {
+            return true;
+        }
+
+        let relative = entry
+            .path()
+            .strip_prefix(root)
+            .unwrap_or_else(|_| entry.path());
+        self.ignore
+            .iter()
+            .any(|p| p.matches(&name) || p.matches_path(relative))
+    }
+
+    fn is_marked(&self, dir: &Path) -> bool {
+        self.markers.iter().any(|m| dir.join(m).exists())
+    }
+
+    /// Walks `root`, returning every directory below it (or the root itself) which holds
+    /// one of the markers. Fails if Ctrl-C is pressed during the walk.
+    pub fn find(&self, root: &Path) -> Result<Vec<PathBuf>, String> {
+        let mut walker = WalkDir::new(root).follow_links(false);
+        if let Some(depth) = self.depth {
+            walker = walker.max_depth(depth);
+        }
+
+        let found = walker
+            .into_iter()
+            .filter_entry(|e| e.file_type().is_dir() && !self.is_ignored(root, e))
+            .take_while(|_| !exec::interrupted())
+            .filter_map(|entry| match entry {
+                Ok(e) => Some(e),
+                Err(e) => {
+                    warn!("Skipping unreadable directory while discovering: {}", e);
+                    None
+                }
+            })
+            .filter(|e| self.is_marked(e.path()))
+            .map(DirEntry::into_path)
+            .collect();
+
+        if exec::interrupted() {
+            return Err("interrupted".to_owned());
+        }
+        Ok(found)
+    }
+}
@@ -0,0 +1,1563 @@
+#![warn(clippy::pedantic)]
+#![warn(clippy::nursery)]
+#![warn(clippy::cargo)]
+#![allow(clippy::default_trait_access)]
+#![allow(clippy::multiple_crate_versions)]
+#![allow(clippy::redundant_pub_crate)]
+#![allow(clippy::uninlined_format_args)]
+#![allow(clippy::unnecessary_semicolon)]
+
+use colored::{Color, Colorize};
+
+use rayon::prelude::*;
+use rayon::ThreadPoolBuilder;
+
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fmt::{Display, Error as FmtError, Formatter};
+use std::fs::OpenOptions;
+use std::io::{self, BufRead, BufReader, PipeReader, Read};
+use std::os::fd::OwnedFd;
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::string::ToString;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::discover;
+use crate::parser;
+use crate::selector::Selector;
+use crate::settings::{NoMatch, OutputMode, Settings, StderrMode};
+use crate::words::Word;
+
+/// Exit code for success.
+pub(crate) const EXIT_SUCCESS: i32 = 0;
+/// Exit code when a command, or a builtin, failed.
+pub(crate) const EXIT_FAILURE: i32 = 1;
+/// Exit code when a line could not be parsed, or a command had no valid targets.
+pub(crate) const EXIT_USAGE: i32 = 2;
+/// Exit code when a command timed out, as used by `timeout(1)`.
+pub(crate) const EXIT_TIMEOUT: i32 = 124;
+/// Exit code when a command could not be started, as used by other shells.
+pub(crate) const EXIT_SPAWN_FAILED: i32 = 127;
+/// Exit code when a command was interrupted with Ctrl-C, as used by other shells.
+pub(crate) const EXIT_INTERRUPTED: i32 = 130;
+
+/// How a process run in a single registered directory came to an end.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) enum Outcome {
+    Exited(i32),
+    Signaled(i32),
+    TimedOut,
+    Interrupted,
+    SpawnFailed(String),
+    /// Part of the command, such as a command substitution, could not be parsed
+    Invalid(String),
+}
+
+impl Outcome {
+    fn from_status(status: ExitStatus) -> Self {
+        if let Some(code) = status.code() {
+            return Self::Exited(code);
+        }
+
+        // Without an exit code, the process must have been killed by a signal
+        Self::Signaled(status.signal().unwrap_or(0))
+    }
+
+    pub const fn success(&self) -> bool {
+        match self {
+            Self::Exited(code) => *code == 0,
+            _ => false,
+        }
+    }
+
+    /// The exit code msh reports for this outcome.
+    pub const fn exit_code(&self) -> i32 {
+        match self {
+            Self::Exited(0) => EXIT_SUCCESS,
+            Self::Exited(_) | Self::Signaled(_) => EXIT_FAILURE,
+            Self::Invalid(_) => EXIT_USAGE,
+            Self::TimedOut => EXIT_TIMEOUT,
+            Self::SpawnFailed(_) => EXIT_SPAWN_FAILED,
+            Self::Interrupted => EXIT_INTERRUPTED,
+        }
+    }
+}
+
+impl Display for Outcome {
+    fn fmt(&self, formatter: &mut Formatter) -> Result<(), FmtError> {
+        match self {
+            Self::Exited(0) => write!(formatter, "ok"),
+            Self::Exited(code) => write!(formatter, "exit {}", code),
+            Self::Signaled(sig) => write!(formatter, "signal {}", sig),
+            Self::TimedOut => write!(formatter, "timed out"),
+            Self::Interrupted => write!(formatter, "interrupted"),
+            Self::SpawnFailed(e) => write!(formatter, "spawn failed: {}", e),
+            Self::Invalid(e) => write!(formatter, "invalid: {}", e),
+        }
+    }
+}
+
+/// The result of running a command in one registered directory.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) struct DirResult {
+    pub path: PathBuf,
+    pub outcome: Outcome,
+}
+
+/// Collected results of a single fan-out across all target directories.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Hash)]
+pub(crate) struct Report {
+    results: Vec<DirResult>,
+}
+
+impl Report {
+    pub const fn new(results: Vec<DirResult>) -> Self {
+        Self { results }
+    }
+
+    pub fn failures(&self) -> impl Iterator<Item = &DirResult> {
+        self.results.iter().filter(|r| !r.outcome.success())
+    }
+
+    /// The exit code for the whole fan-out: the highest code of any directory, so that an
+    /// interruption outranks a spawn failure, which outranks a timeout, an invalid command,
+    /// then a failure.
+    pub fn exit_code(&self) -> i32 {
+        self.results
+            .iter()
+            .map(|r| r.outcome.exit_code())
+            .max()
+            .unwrap_or(EXIT_SUCCESS)
+    }
+}
+
+impl Display for Report {
+    fn fmt(&self, formatter: &mut Formatter) -> Result<(), FmtError> {
+        let failed = self.failures().count();
+        let ok = self.results.len() - failed;
+
+        let summary = format!("{} ok, {} failed", ok, failed);
+        if failed == 0 {
+            write!(formatter, "{}", summary.green())?;
+        } else {
+            write!(formatter, "{}", summary.red())?;
+        }
+
+        for result in self.failures() {
+            write!(
+                formatter,
+                "\n  {} ({})",
+                result.path.display(),
+                result.outcome
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// A command line bound for the executor, along with any per-command setting overrides and
+/// target selection.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) struct Job {
+    pub overrides: Vec<(String, String)>,
+    pub selector: Option<Selector>,
+    /// Directories given with `@{DIR,...}`, run in instead of any registered directories
+    pub paths: Vec<String>,
+    /// Directories given with `!EXCLUSION`, left out of this run
+    pub excludes: Vec<Exclusion>,
+    pub list: CommandList,
+}
+
+impl Job {
+    /// Whether the command line had no prefixes, making it eligible to be a builtin.
+    pub const fn is_plain(&self) -> bool {
+        self.overrides.is_empty()
+            && self.selector.is_none()
+            && self.paths.is_empty()
+            && self.excludes.is_empty()
+    }
+}
+
+/// Pipelines run one after another in each directory, each depending on the status of the
+/// one before it. The connectors are evaluated separately in every directory.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) struct CommandList {
+    pub first: Pipeline,
+    pub rest: Vec<(Connector, Pipeline)>,
+}
+
+impl CommandList {
+    /// A list of just one command, without redirections, whose words are taken literally.
+    pub fn single(args: Vec<String>) -> Self {
+        Self {
+            first: Pipeline {
+                commands: vec![SimpleCommand {
+                    args: args.into_iter().map(Word::literal).collect(),
+                    redirects: Vec::new(),
+                }],
+            },
+            rest: Vec::new(),
+        }
+    }
+
+    /// The words of a list of just one command without redirections or substitutions,
+    /// which may name a builtin.
+    pub fn simple_args(&self) -> Option<Vec<String>> {
+        match self.first.commands.as_slice() {
+            [command]
+                if command.redirects.is_empty()
+                    && self.rest.is_empty()
+                    && command
+                        .args
+                        .iter()
+                        .all(|w| w.substitutions().next().is_none()) =>
+            {
+                Some(command.args.iter().map(Word::text).collect())
+            }
+            _ => None,
+        }
+    }
+
+    /// Every pipeline in the list, in order.
+    pub fn pipelines(&self) -> impl Iterator<Item = &Pipeline> {
+        std::iter::once(&self.first).chain(self.rest.iter().map(|(_, p)| p))
+    }
+}
+
+/// How a pipeline in a command list depends on the one before it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) enum Connector {
+    /// `;`, always running
+    Then,
+    /// `&&`, running after a success
+    And,
+    /// `||`, running after a failure
+    Or,
+}
+
+impl Connector {
+    /// Whether the pipeline following this connector runs, given how the last pipeline run
+    /// came to an end.
+    pub const fn runs_after(self, outcome: &Outcome) -> bool {
+        match self {
+            Self::Then => true,
+            Self::And => outcome.success(),
+            Self::Or => !outcome.success(),
+        }
+    }
+}
+
+/// Commands run together in each directory, each one's standard output feeding the next
+/// one's standard input.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) struct Pipeline {
+    pub commands: Vec<SimpleCommand>,
+}
+
+/// A program with its arguments and redirections. There is always at least one word, though
+/// glob patterns among them are only expanded in each directory.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) struct SimpleCommand {
+    pub args: Vec<Word>,
+    /// Applied in order, so `> FILE 2>&1` sends both streams to FILE
+    pub redirects: Vec<Redirect>,
+}
+
+/// A change to where a command reads or writes, with any file relative to each directory.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) enum Redirect {
+    /// `< FILE`
+    Input(String),
+    /// `> FILE`, or `>> FILE` to append
+    Output { path: String, append: bool },
+    /// `2> FILE`, or `2>> FILE` to append
+    Error { path: String, append: bool },
+    /// `2>&1`
+    ErrorToOutput,
+}
+
+/// Directories to leave out of a single run.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) enum Exclusion {
+    /// A directory, or glob pattern of directories
+    Path(String),
+    /// The directories in a group, carrying a tag, or given a name
+    Name(String),
+}
+
+impl Exclusion {
+    /// Reads the word following a `!`: it names a path if it contains a `/`, starts with a
+    /// `.` or is a glob pattern, and a group, tag or directory name otherwise.
+    pub fn parse(word: &str) -> Self {
+        if word.contains('/') || word.starts_with('.') || discover::is_pattern(word) {
+            Self::Path(word.to_owned())
+        } else {
+            Self::Name(word.to_owned())
+        }
+    }
+}
+
+/// A directory to run in, with the label used to prefix its streamed output.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) struct Target {
+    pub path: PathBuf,
+    pub name: Option<String>,
+    pub env: BTreeMap<String, String>,
+    pub label: String,
+}
+
+impl Target {
+    pub const fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            name: None,
+            env: BTreeMap::new(),
+            label: String::new(),
+        }
+    }
+}
+
+const LABEL_COLORS: [Color; 5] = [
+    Color::Cyan,
+    Color::Green,
+    Color::Yellow,
+    Color::Blue,
+    Color::Magenta,
+];
+
+/// Labels each target with its padded and colored name, or directory name if it has none.
+pub(crate) fn label_targets(targets: &mut [Target]) {
+    let names: Vec<String> = targets
+        .iter()
+        .map(|t| {
+            t.name.clone().unwrap_or_else(|| {
+                t.path
+                    .file_name()
+                    .map_or_else(|| t.path.to_string_lossy(), |n| n.to_string_lossy())
+                    .into_owned()
+            })
+        })
+        .collect();
+    let width = names.iter().map(|n| n.chars().count()).max().unwrap_or(0);
+
+    for (i, (target, name)) in targets.iter_mut().zip(names).enumerate() {
+        target.label = format!("{:<width$}", name, width = width)
+            .color(LABEL_COLORS[i % LABEL_COLORS.len()])
+            .to_string();
+    }
+}
+
+fn header(target: &Target, outcome: &Outcome) -> String {
+    let status = if outcome.success() {
+        outcome.to_string().green()
+    } else {
+        outcome.to_string().red()
+    };
+    let path = target.path.to_string_lossy().cyan().bold();
+    target.name.as_ref().map_or_else(
+        || format!("{} [{}]:", path, status),
+        |name| format!("{} ({}) [{}]:", path, name, status),
+    )
+}
+
+/// Which pipe of the child a line of output was read from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Stream {
+    Stdout,
+    Stderr,
+}
+
+/// Reads `reader` line by line on a new thread, sending each line down `tx` tagged with
+/// `stream`, so lines from both pipes arrive in a single channel in arrival order.
+fn forward<R>(reader: R, stream: Stream, tx: Sender<(Stream, String)>) -> JoinHandle<()>
+where
+    R: Read + Send + 'static,
+{
+    thread::spawn(move || {
+        let mut reader = BufReader::new(reader);
+        let mut buf = Vec::new();
+        loop {
+            buf.clear();
+            match reader.read_until(b'\n', &mut buf) {
+                Ok(0) => break,
+                Ok(_) => {
+                    let line = String::from_utf8_lossy(&buf).trim_end().to_owned();
+                    if tx.send((stream, line)).is_err() {
+                        break;
+                    }
+                }
+                Err(e) => {
+                    warn!("Failed reading child {:?}: {}", stream, e);
+                    break;
+                }
+            }
+        }
+    })
+}
+
+fn render(lines: &[(Stream, String)], mode: StderrMode) -> String {
+    let stdout = lines.iter().filter(|(s, _)| *s == Stream::Stdout);
+    let stderr = lines.iter().filter(|(s, _)| *s == Stream::Stderr);
+    let ordered: Vec<&(Stream, String)> = match mode {
+        StderrMode::Interleave => lines.iter().collect(),
+        StderrMode::Separate => stdout.chain(stderr).collect(),
+        StderrMode::Hide => stdout.collect(),
+    };
+
+    let mut out = String::new();
+    for (stream, line) in ordered {
+        match stream {
+            Stream::Stdout => out.push_str(line),
+            Stream::Stderr => out.push_str(&line.red().to_string()),
+        };
+        out.push('\n');
+    }
+    out
+}
+
+/// How often a running child is checked on while no output is arriving.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Sends `signal` to the process group led by `child`.
+fn signal_group(child: &Child, signal: libc::c_int) {
+    // The first command of each pipeline leads its group, so its pid is also the group id
+    let pgid = match libc::pid_t::try_from(child.id()) {
+        Ok(p) => p,
+        Err(e) => {
+            warn!("Child pid {} does not fit in pid_t: {}", child.id(), e);
+            return;
+        }
+    };
+
+    trace!("Sending signal {} to process group {}", signal, pgid);
+    if unsafe { libc::killpg(pgid, signal) } != 0 {
+        debug!(
+            "Could not signal process group {}: {}",
+            pgid,
+            io::Error::last_os_error()
+        );
+    }
+}
+
+/// The number of times Ctrl-C has been pressed since the interrupts were last cleared.
+static INTERRUPTS: AtomicUsize = AtomicUsize::new(0);
+
+/// Installs a SIGINT handler which records Ctrl-C presses rather than terminating msh, so
+/// that the executor can forward them to the running children instead.
+pub(crate) fn install_interrupt_handler() -> Result<(), String> {
+    ctrlc::set_handler(|| {
+        INTERRUPTS.fetch_add(1, Ordering::SeqCst);
+    })
+    .map_err(|e| format!("Could not install Ctrl-C handler: {}", e))
+}
+
+/// Whether Ctrl-C has been pressed since the interrupts were last cleared. Builtins which
+/// may run for a while check this to stop early.
+pub(crate) fn interrupted() -> bool {
+    INTERRUPTS.load(Ordering::SeqCst) > 0
+}
+
+/// Forgets any Ctrl-C presses, once the line they interrupted has been dealt with.
+pub(crate) fn clear_interrupts() {
+    INTERRUPTS.store(0, Ordering::SeqCst);
+}
+
+/// Enforces the `timeout` setting on a child: SIGTERM once the deadline passes, then
+/// SIGKILL if it is still around after the `grace` period. Also forwards Ctrl-C as SIGINT,
+/// escalating to SIGKILL if it is pressed again.
+struct Watchdog {
+    deadline: Option<Instant>,
+    grace: Duration,
+    terminated_at: Option<Instant>,
+    killed: bool,
+    interrupts: usize,
+}
+
+impl Watchdog {
+    fn new(settings: &Settings) -> Self {
+        Self {
+            deadline: settings.timeout.0.map(|t| Instant::now() + t),
+            grace: settings.grace.0,
+            terminated_at: None,
+            killed: false,
+            interrupts: 0,
+        }
+    }
+
+    fn check(&mut self, child: &Child) {
+        let interrupts = INTERRUPTS.load(Ordering::SeqCst);
+        if interrupts > self.interrupts {
+            self.interrupts = interrupts;
+            if interrupts == 1 {
+                debug!("Forwarding interrupt to child {}", child.id());
+                signal_group(child, libc::SIGINT);
+            } else {
+                debug!("Interrupted again, sending SIGKILL to child {}", child.id());
+                signal_group(child, libc::SIGKILL);
+            }
+        }
+
+        let now = Instant::now();
+        match self.terminated_at {
+            None => {
+                if self.deadline.is_some_and(|d| now >= d) {
+                    debug!("Child {} timed out, sending SIGTERM", child.id());
+                    signal_group(child, libc::SIGTERM);
+                    self.terminated_at = Some(now);
+                }
+            }
+            Some(at) if !self.killed && now >= at + self.grace => {
+                debug!(
+                    "Child {} outlived its grace period, sending SIGKILL",
+                    child.id()
+                );
+                signal_group(child, libc::SIGKILL);
+                self.killed = true;
+            }
+            Some(_) => {}
+        }
+    }
+
+    const fn timed_out(&self) -> bool {
+        self.terminated_at.is_some()
+    }
+
+    const fn interrupted(&self) -> bool {
+        self.interrupts > 0
+    }
+}
+
+fn print_streamed(label: &str, stream: Stream, line: &str) {
+    match stream {
+        Stream::Stdout => println!("{} | {}", label, line),
+        Stream::Stderr => println!("{} | {}", label, line.red()),
+    };
+}
+
+/// The processes of a pipeline started in one directory, along with the pipes its output
+/// is read from.
+struct Running {
+    children: Vec<Child>,
+    stdout: Option<PipeReader>,
+    stderr: Option<PipeReader>,
+}
+
+/// The standard streams of a command, where `None` is the null device.
+struct Streams {
+    stdin: Option<OwnedFd>,
+    stdout: Option<OwnedFd>,
+    stderr: Option<OwnedFd>,
+}
+
+/// A pipe read by msh, unless the stream is discarded.
+fn output_pipe(discard: bool) -> io::Result<(Option<PipeReader>, Option<OwnedFd>)> {
+    if discard {
+        return Ok((None, None));
+    }
+    let (reader, writer) = io::pipe()?;
+    Ok((Some(reader), Some(writer.into())))
+}
+
+fn open_in(dir: &Path, path: &str, options: &OpenOptions) -> io::Result<OwnedFd> {
+    options
+        .open(dir.join(path))
+        .map(OwnedFd::from)
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path, e)))
+}
+
+fn write_options(append: bool) -> OpenOptions {
+    let mut options = OpenOptions::new();
+    options.write(true).create(true);
+    if append {
+        options.append(true);
+    } else {
+        options.truncate(true);
+    }
+    options
+}
+
+impl Streams {
+    /// Applies each redirection in turn, opening files relative to `dir`.
+    fn redirect(mut self, redirects: &[Redirect], dir: &Path) -> io::Result<Self> {
+        for redirect in redirects {
+            match redirect {
+                Redirect::Input(path) => {
+                    self.stdin = Some(open_in(dir, path, OpenOptions::new().read(true))?);
+                }
+                Redirect::Output { path, append } => {
+                    self.stdout = Some(open_in(dir, path, &write_options(*append))?);
+                }
+                Redirect::Error { path, append } => {
+                    self.stderr = Some(open_in(dir, path, &write_options(*append))?);
+                }
+                Redirect::ErrorToOutput => {
+                    self.stderr = self.stdout.as_ref().map(OwnedFd::try_clone).transpose()?;
+                }
+            }
+        }
+        Ok(self)
+    }
+}
+
+fn stdio(fd: Option<OwnedFd>) -> Stdio {
+    fd.map_or_else(Stdio::null, Stdio::from)
+}
+
+/// Expands the glob patterns among `words` relative to `dir`, with `nomatch` deciding what
+/// becomes of those matching nothing.
+fn expand_args(words: &[Word], dir: &Path, nomatch: NoMatch) -> io::Result<Vec<String>> {
+    let mut args = Vec::with_capacity(words.len());
+    for word in words {
+        match word.glob(dir) {
+            None => args.push(word.text()),
+            Some(matches) if !matches.is_empty() => args.extend(matches),
+            Some(_) => match nomatch {
+                NoMatch::Literal => args.push(word.text()),
+                NoMatch::Null => {}
+                NoMatch::Fail => {
+                    return Err(io::Error::other(format!("no match for {}", word.text())));
+                }
+            },
+        }
+    }
+    if args.is_empty() {
+        return Err(io::Error::other("no command left after glob expansion"));
+    }
+    Ok(args)
+}
+
+/// A command of a pipeline once its words have been expanded in a directory.
+struct Expanded<'a> {
+    args: Vec<String>,
+    redirects: &'a [Redirect],
+}
+
+/// Why a pipeline could not be started in a directory.
+#[derive(Debug)]
+enum StartError {
+    /// A file named by a redirection could not be opened, which fails only that directory.
+    Redirect(io::Error),
+    /// A command could not be executed.
+    Spawn(io::Error),
+}
+
+impl From<io::Error> for StartError {
+    fn from(e: io::Error) -> Self {
+        Self::Spawn(e)
+    }
+}
+
+/// Starts one command of a pipeline in the target's directory, joining the process group of
+/// `leader`, or leading a new group when it is zero.
+fn start(
+    command: &Expanded,
+    streams: Streams,
+    leader: u32,
+    target: &Target,
+) -> Result<Child, StartError> {
+    let args = &command.args;
+    let streams = streams
+        .redirect(command.redirects, &target.path)
+        .map_err(StartError::Redirect)?;
+
+    // The first command leads a new process group, which the rest join, so that the whole
+    // pipeline and anything it spawns can be signalled together. Being outside the
+    // terminal's foreground group, it must not read from the terminal either.
+    Command::new(&args[0])
+        .args(args.iter().skip(1))
+        .current_dir(&target.path)
+        .envs(&target.env)
+        .process_group(i32::try_from(leader).unwrap_or(0))
+        .stdin(stdio(streams.stdin))
+        .stdout(stdio(streams.stdout))
+        .stderr(stdio(streams.stderr))
+        .spawn()
+        .map_err(|e| StartError::Spawn(io::Error::new(e.kind(), format!("{}: {}", args[0], e))))
+}
+
+/// Starts each command of a pipeline in the target's directory, with its environment, and
+/// pipes between them. A command which cannot be started stops the ones already started.
+fn spawn(
+    commands: &[Expanded],
+    target: &Target,
+    settings: &Settings,
+) -> Result<Running, StartError> {
+    let quiet = settings.output == OutputMode::Quiet;
+    let (stdout, mut stdout_writer) = output_pipe(quiet)?;
+    // Every command writes its stderr to the same pipe, so lines arrive in order
+    let (stderr, stderr_writer) = output_pipe(quiet || settings.stderr == StderrMode::Hide)?;
+
+    let mut children: Vec<Child> = Vec::with_capacity(commands.len());
+    let mut input = None;
+    for (i, command) in commands.iter().enumerate() {
+        let leader = children.first().map_or(0, Child::id);
+        let mut start_stage = || {
+            let (next_input, output) = if i + 1 == commands.len() {
+                (None, stdout_writer.take())
+            } else {
+                let (reader, writer) = io::pipe()?;
+                (Some(reader), Some(writer.into()))
+            };
+            let streams = Streams {
+                stdin: input.take().map(OwnedFd::from),
+                stdout: output,
+                stderr: stderr_writer.as_ref().map(OwnedFd::try_clone).transpose()?,
+            };
+            input = next_input;
+            start(command, streams, leader, target)
+        };
+
+        match start_stage() {
+            Ok(child) => children.push(child),
+            Err(e) => {
+                if let Some(first) = children.first() {
+                    signal_group(first, libc::SIGKILL);
+                }
+                for mut child in children {
+                    let _ = child.wait();
+                }
+                return Err(e);
+            }
+        };
+    }
+
+    Ok(Running {
+        children,
+        stdout,
+        stderr,
+    })
+}
+
+/// The status of a finished pipeline: that of its last command or, with `pipefail`, of
+/// the last command to fail.
+fn pipeline_status(statuses: &[ExitStatus], pipefail: bool) -> ExitStatus {
+    let last = statuses[statuses.len() - 1];
+    if pipefail {
+        statuses
+            .iter()
+            .rev()
+            .find(|s| !s.success())
+            .copied()
+            .unwrap_or(last)
+    } else {
+        last
+    }
+}
+
+/// Reports a command which could not be started in a directory.
+fn spawn_failed(path: &Path, e: &io::Error) -> Outcome {
+    eprintln!(
+        "Could not execute process on dir: {}, failed with error: {}",
+        path.display(),
+        e
+    );
+    Outcome::SpawnFailed(e.to_string())
+}
+
+/// Shows an error of msh's own with the output of the directory it happened in.
+fn report_error(
+    target: &Target,
+    settings: &Settings,
+    e: &dyn Display,
+    lines: &mut Vec<(Stream, String)>,
+) {
+    let message = format!("msh: {}", e);
+    match settings.output {
+        OutputMode::Stream => print_streamed(&target.label, Stream::Stderr, &message),
+        OutputMode::Grouped => lines.push((Stream::Stderr, message)),
+        OutputMode::Quiet => {}
+    }
+}
+
+/// Reports a redirected file which could not be opened with the output of the directory,
+/// failing the command there like any other.
+fn redirect_failed(
+    target: &Target,
+    settings: &Settings,
+    e: &io::Error,
+    lines: &mut Vec<(Stream, String)>,
+) -> Outcome {
+    report_error(target, settings, e, lines);
+    Outcome::Exited(EXIT_FAILURE)
+}
+
+/// Runs the command of a `$(...)` substitution in the target's directory, under the same
+/// watchdog as the command it is part of, returning its output without trailing newlines.
+/// Its stderr is shown with the output of the outer command.
+fn substitute(
+    source: &str,
+    target: &Target,
+    settings: &Settings,
+    watchdog: &mut Watchdog,
+    lines: &mut Vec<(Stream, String)>,
+) -> Result<String, Outcome> {
+    let list = parser::parse_substitution(source).map_err(|e| {
+        let e = format!("in command substitution $({}): {}", source, e);
+        report_error(target, settings, &e, lines);
+        Outcome::Invalid(e)
+    })?;
+    let mut inner = settings.clone();
+    inner.output = OutputMode::Grouped;
+
+    let mut captured = Vec::new();
+    let outcome = run_list(&list, target, &inner, watchdog, &mut captured);
+    if matches!(outcome, Outcome::Interrupted | Outcome::TimedOut) {
+        return Err(outcome);
+    }
+    debug!("Substitution $({}) finished: {:?}", source, outcome);
+
+    let mut output = String::new();
+    for (stream, line) in captured {
+        match (stream, settings.output) {
+            (Stream::Stdout, _) => {
+                output.push_str(&line);
+                output.push('\n');
+            }
+            (Stream::Stderr, OutputMode::Stream) => print_streamed(&target.label, stream, &line),
+            (Stream::Stderr, OutputMode::Grouped) => lines.push((stream, line)),
+            (Stream::Stderr, OutputMode::Quiet) => {}
+        }
+    }
+    Ok(output.trim_end_matches('\n').to_owned())
+}
+
+/// Expands the words of each command in a pipeline for the target's directory: running
+/// command substitutions, then matching glob patterns.
+fn expand<'a>(
+    pipeline: &'a Pipeline,
+    target: &Target,
+    settings: &Settings,
+    watchdog: &mut Watchdog,
+    lines: &mut Vec<(Stream, String)>,
+) -> Result<Vec<Expanded<'a>>, Outcome> {
+    let mut commands = Vec::with_capacity(pipeline.commands.len());
+    for command in &pipeline.commands {
+        let mut words = Vec::with_capacity(command.args.len());
+        for word in &command.args {
+            words.extend(
+                word.substitute(&mut |source| {
+                    substitute(source, target, settings, watchdog, lines)
+                })?,
+            );
+        }
+        let args = expand_args(&words, &target.path, settings.nomatch)
+            .map_err(|e| spawn_failed(&target.path, &e))?;
+        commands.push(Expanded {
+            args,
+            redirects: &command.redirects,
+        });
+    }
+    Ok(commands)
+}
+
+/// Runs one pipeline of a command list in a directory, under the list's `watchdog`. Output
+/// is printed as it arrives in stream mode, and otherwise added to `lines`.
+fn run_pipeline(
+    pipeline: &Pipeline,
+    target: &Target,
+    settings: &Settings,
+    watchdog: &mut Watchdog,
+    lines: &mut Vec<(Stream, String)>,
+) -> Outcome {
+    let path = &target.path;
+    let streaming = settings.output == OutputMode::Stream;
+
+    if interrupted() {
+        debug!("Interrupted before starting in {}", path.display());
+        return Outcome::Interrupted;
+    }
+    let commands = match expand(pipeline, target, settings, watchdog, lines) {
+        Ok(c) => c,
+        Err(outcome) => return outcome,
+    };
+    let mut running = match spawn(&commands, target, settings) {
+        Ok(c) => c,
+        Err(StartError::Redirect(e)) => return redirect_failed(target, settings, &e, lines),
+        Err(StartError::Spawn(e)) => return spawn_failed(path, &e),
+    };
+
+    let (tx, rx) = mpsc::channel();
+    let mut readers = Vec::with_capacity(2);
+    if let Some(out) = running.stdout.take() {
+        readers.push(forward(out, Stream::Stdout, tx.clone()));
+    }
+    if let Some(err) = running.stderr.take() {
+        readers.push(forward(err, Stream::Stderr, tx.clone()));
+    }
+    drop(tx); // The channel closes once every reader has hung up
+
+    let mut open = true;
+    let mut statuses = vec![None; running.children.len()];
+    let status = loop {
+        if open {
+            match rx.recv_timeout(POLL_INTERVAL) {
+                Ok((stream, line)) => {
+                    if streaming {
+                        print_streamed(&target.label, stream, &line);
+                    } else {
+                        lines.push((stream, line));
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => open = false,
+            }
+        } else {
+            match wait_all(&mut running.children, &mut statuses) {
+                Ok(Some(finished)) => break Ok(pipeline_status(&finished, settings.pipefail.0)),
+                Ok(None) => thread::sleep(POLL_INTERVAL),
+                Err(e) => break Err(e),
+            }
+        }
+        watchdog.check(&running.children[0]);
+    };
+    for reader in readers {
+        if reader.join().is_err() {
+            warn!("Output reader thread panicked in {}", path.display());
+        }
+    }
+
+    let outcome = match status {
+        Ok(_) if watchdog.interrupted() => Outcome::Interrupted,
+        Ok(_) if watchdog.timed_out() => Outcome::TimedOut,
+        Ok(status) => Outcome::from_status(status),
+        Err(e) => Outcome::SpawnFailed(e.to_string()),
+    };
+    debug!("Pipeline in {} finished: {:?}", path.display(), outcome);
+    outcome
+}
+
+/// Runs the pipelines of `list` in a directory as their connectors allow, returning how
+/// the last one to run came to an end.
+fn run_list(
+    list: &CommandList,
+    target: &Target,
+    settings: &Settings,
+    watchdog: &mut Watchdog,
+    lines: &mut Vec<(Stream, String)>,
+) -> Outcome {
+    let mut outcome = run_pipeline(&list.first, target, settings, watchdog, lines);
+    for (connector, pipeline) in &list.rest {
+        if matches!(outcome, Outcome::Interrupted | Outcome::TimedOut) {
+            break;
+        }
+        if connector.runs_after(&outcome) {
+            outcome = run_pipeline(pipeline, target, settings, watchdog, lines);
+        }
+    }
+    outcome
+}
+
+/// Runs a command list in a single directory. In grouped mode the rendered output block is
+/// returned rather than printed, so that the caller can print blocks in target order.
+fn run_in_dir(
+    list: &CommandList,
+    target: &Target,
+    settings: &Settings,
+) -> (DirResult, Option<String>) {
+    let path = &target.path;
+    let mut watchdog = Watchdog::new(settings);
+    let mut lines = Vec::new();
+
+    let outcome = run_list(list, target, settings, &mut watchdog, &mut lines);
+    debug!("Commands in {} finished: {:?}", path.display(), outcome);
+
+    let block = match settings.output {
+        OutputMode::Stream => {
+            println!("{} | [{}]", target.label, outcome);
+            None
+        }
+        OutputMode::Quiet => None,
+        OutputMode::Grouped => {
+            let output = render(&lines, settings.stderr);
+            if output.trim().is_empty() && outcome.success() {
+                None
+            } else {
+                Some(format!("{}\n{}", header(target, &outcome), output))
+            }
+        }
+    };
+
+    let result = DirResult {
+        path: path.to_owned(),
+        outcome,
+    };
+    (result, block)
+}
+
+/// Checks on every child not yet known to have exited, returning all of their statuses once
+/// they all have.
+fn wait_all(
+    children: &mut [Child],
+    statuses: &mut [Option<ExitStatus>],
+) -> io::Result<Option<Vec<ExitStatus>>> {
+    for (child, status) in children.iter_mut().zip(statuses.iter_mut()) {
+        if status.is_none() {
+            *status = child.try_wait()?;
+        }
+    }
+    Ok(statuses.iter().copied().collect())
+}
+
+/// Prints output blocks in target order, holding back any block whose predecessors are
+/// still running.
+fn print_in_order(rx: Receiver<(usize, Option<String>)>) {
+    let mut pending = BTreeMap::new();
+    let mut next = 0;
+
+    for (i, block) in rx {
+        pending.insert(i, block);
+        while let Some(block) = pending.remove(&next) {
+            if let Some(b) = block {
+                println!("{}", b);
+            }
+            next += 1;
+        }
+    }
+}
+
+/// Runs `list` in every target, at most `jobs` at a time, returning the results in target
+/// order. A single job runs the targets one after another, in order.
+pub(crate) fn run_all(
+    list: &CommandList,
+    targets: &[Target],
+    settings: &Settings,
+) -> Result<Vec<DirResult>, String> {
+    let pool = ThreadPoolBuilder::new()
+        .num_threads(settings.jobs.0)
+        .build()
+        .map_err(|e| format!("Could not start executor threads: {}", e))?;
+    let (tx, rx) = mpsc::channel();
+
+    let run = move |tx: &mut Sender<(usize, Option<String>)>, (i, target): (usize, &Target)| {
+        let (result, block) = run_in_dir(list, target, settings);
+        if tx.send((i, block)).is_err() {
+            warn!("Output printer hung up before {}", target.path.display());
+        }
+        result
+    };
+
+    thread::scope(|scope| {
+        let runner = scope.spawn(move || {
+            if settings.jobs.0 == 1 {
+                debug!("Running serially");
+                let mut tx = tx;
+                targets
+                    .iter()
+                    .enumerate()
+                    .map(|t| run(&mut tx, t))
+                    .collect()
+            } else {
+                pool.install(|| targets.par_iter().enumerate().map_with(tx, run).collect())
+            }
+        });
+
+        print_in_order(rx);
+        Ok(runner.join().expect("Executor thread panicked"))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::{Mutex, MutexGuard, PoisonError};
+
+    use crate::settings::{Grace, Jobs, Switch, Timeout};
+    use crate::words::{self, Token};
+
+    /// Held by tests which run processes, as they share the count of interrupts.
+    static PROCESSES: Mutex<()> = Mutex::new(());
+
+    fn lock() -> MutexGuard<'static, ()> {
+        PROCESSES.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// A fresh, empty directory for one test.
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("msh-exec-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Runs the command list `source` in `dir`, returning how it came to an end and its
+    /// output.
+    fn run(source: &str, dir: &Path, settings: &Settings) -> (Outcome, Vec<(Stream, String)>) {
+        let list = parser::parse_substitution(source).unwrap();
+        let target = Target::new(dir.to_owned());
+        let mut lines = Vec::new();
+        let mut watchdog = Watchdog::new(settings);
+        let outcome = run_list(&list, &target, settings, &mut watchdog, &mut lines);
+        (outcome, lines)
+    }
+
+    fn stdout(lines: &[(Stream, String)]) -> Vec<&str> {
+        lines
+            .iter()
+            .filter(|(stream, _)| *stream == Stream::Stdout)
+            .map(|(_, line)| line.as_str())
+            .collect()
+    }
+
+    fn sleep() -> Child {
+        Command::new("sleep")
+            .arg("10")
+            .process_group(0)
+            .spawn()
+            .unwrap()
+    }
+
+    fn report(outcomes: &[Outcome]) -> Report {
+        Report::new(
+            outcomes
+                .iter()
+                .map(|outcome| DirResult {
+                    path: PathBuf::from("/"),
+                    outcome: outcome.clone(),
+                })
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn outcomes_have_documented_exit_codes() {
+        assert_eq!(Outcome::Exited(0).exit_code(), 0);
+        assert_eq!(Outcome::Exited(3).exit_code(), 1);
+        assert_eq!(Outcome::Signaled(libc::SIGTERM).exit_code(), 1);
+        assert_eq!(Outcome::TimedOut.exit_code(), 124);
+        assert_eq!(Outcome::SpawnFailed(String::new()).exit_code(), 127);
+        assert_eq!(Outcome::Interrupted.exit_code(), 130);
+    }
+
+    #[test]
+    fn report_exit_code_is_the_highest_of_its_directories() {
+        assert_eq!(report(&[]).exit_code(), EXIT_SUCCESS);
+        assert_eq!(
+            report(&[Outcome::Exited(0), Outcome::Exited(0)]).exit_code(),
+            EXIT_SUCCESS
+        );
+        assert_eq!(
+            report(&[Outcome::Exited(0), Outcome::Exited(2)]).exit_code(),
+            EXIT_FAILURE
+        );
+        assert_eq!(
+            report(&[Outcome::SpawnFailed(String::new()), Outcome::TimedOut]).exit_code(),
+            EXIT_SPAWN_FAILED
+        );
+        assert_eq!(
+            report(&[Outcome::Exited(1), Outcome::Interrupted, Outcome::TimedOut]).exit_code(),
+            EXIT_INTERRUPTED
+        );
+    }
+
+    #[test]
+    fn watchdog_forwards_interrupts_then_kills() {
+        let _processes = lock();
+        let mut watchdog = Watchdog::new(&Settings::default());
+
+        let mut first = sleep();
+        INTERRUPTS.store(1, Ordering::SeqCst);
+        watchdog.check(&first);
+        assert_eq!(first.wait().unwrap().signal(), Some(libc::SIGINT));
+        assert!(watchdog.interrupted());
+
+        let mut second = sleep();
+        INTERRUPTS.store(2, Ordering::SeqCst);
+        watchdog.check(&second);
+        clear_interrupts();
+        assert_eq!(second.wait().unwrap().signal(), Some(libc::SIGKILL));
+        assert!(!watchdog.timed_out());
+    }
+
+    #[test]
+    fn pipeline_status_is_the_last_stage_unless_pipefail() {
+        let _processes = lock();
+        let dir = temp_dir("pipefail");
+        let mut settings = Settings::default();
+        assert_eq!(
+            run("sh -c 'exit 3' | true", &dir, &settings).0,
+            Outcome::Exited(0)
+        );
+        assert_eq!(run("true | false", &dir, &settings).0, Outcome::Exited(1));
+
+        settings.pipefail = Switch(true);
+        assert_eq!(
+            run("sh -c 'exit 3' | true", &dir, &settings).0,
+            Outcome::Exited(3)
+        );
+        assert_eq!(
+            run("false | sh -c 'exit 3' | true", &dir, &settings).0,
+            Outcome::Exited(3)
+        );
+        assert_eq!(run("true | true", &dir, &settings).0, Outcome::Exited(0));
+    }
+
+    #[test]
+    fn pipelines_feed_each_stage_into_the_next() {
+        let _processes = lock();
+        let dir = temp_dir("pipeline");
+        let (outcome, lines) = run(
+            "printf 'b\\na\\nb\\n' | sort | uniq -c | wc -l",
+            &dir,
+            &Settings::default(),
+        );
+        assert_eq!(outcome, Outcome::Exited(0));
+        assert_eq!(
+            stdout(&lines).iter().map(|l| l.trim()).collect::<Vec<_>>(),
+            ["2"]
+        );
+
+        // Every stage writes its stderr to the same place
+        let (_, lines) = run(
+            "sh -c 'echo one >&2' | sh -c 'echo two >&2'",
+            &dir,
+            &Settings::default(),
+        );
+        assert_eq!(lines.len(), 2);
+        assert!(lines.iter().all(|(stream, _)| *stream == Stream::Stderr));
+    }
+
+    #[test]
+    fn connectors_run_after_matching_outcomes() {
+        let failed = Outcome::Exited(1);
+        let ok = Outcome::Exited(0);
+        assert!(Connector::Then.runs_after(&ok) && Connector::Then.runs_after(&failed));
+        assert!(Connector::And.runs_after(&ok) && !Connector::And.runs_after(&failed));
+        assert!(!Connector::Or.runs_after(&ok) && Connector::Or.runs_after(&failed));
+        assert!(Connector::Or.runs_after(&Outcome::SpawnFailed(String::new())));
+    }
+
+    #[test]
+    fn command_lists_short_circuit() {
+        let _processes = lock();
+        let dir = temp_dir("lists");
+        let settings = Settings::default();
+
+        let (outcome, lines) = run("false && echo and; echo then", &dir, &settings);
+        assert_eq!(
+            (outcome, stdout(&lines)),
+            (Outcome::Exited(0), vec!["then"])
+        );
+        let (outcome, lines) = run("false || echo or", &dir, &settings);
+        assert_eq!((outcome, stdout(&lines)), (Outcome::Exited(0), vec!["or"]));
+        let (outcome, lines) = run("true || echo or", &dir, &settings);
+        assert_eq!((outcome, stdout(&lines)), (Outcome::Exited(0), vec![]));
+        let (outcome, _) = run(
+            "true && sh -c 'exit 3' || false; sh -c 'exit 4' && true",
+            &dir,
+            &settings,
+        );
+        assert_eq!(outcome, Outcome::Exited(4));
+    }
+
+    #[test]
+    fn redirections_open_files_in_the_directory() {
+        let _processes = lock();
+        let dir = temp_dir("redirect");
+        let settings = Settings::default();
+        let read = |name: &str| std::fs::read_to_string(dir.join(name)).unwrap();
+
+        let (outcome, lines) = run("echo one > out.txt; echo two >> out.txt", &dir, &settings);
+        assert_eq!((outcome, lines), (Outcome::Exited(0), vec![]));
+        assert_eq!(read("out.txt"), "one\ntwo\n");
+        run("echo three > out.txt", &dir, &settings);
+        assert_eq!(read("out.txt"), "three\n");
+
+        let (_, lines) = run("cat < out.txt", &dir, &settings);
+        assert_eq!(stdout(&lines), ["three"]);
+
+        run(
+            "sh -c 'echo out; echo err >&2' > both.txt 2>&1",
+            &dir,
+            &settings,
+        );
+        assert_eq!(read("both.txt"), "out\nerr\n");
+        run(
+            "sh -c 'echo err >&2' 2> err.txt; sh -c 'echo again >&2' 2>> err.txt",
+            &dir,
+            &settings,
+        );
+        assert_eq!(read("err.txt"), "err\nagain\n");
+    }
+
+    #[test]
+    fn unopenable_redirections_fail_the_directory() {
+        let _processes = lock();
+        let dir = temp_dir("redirect-fail");
+        let settings = Settings::default();
+
+        let (outcome, lines) = run("echo lost > missing/out.txt", &dir, &settings);
+        assert_eq!(outcome, Outcome::Exited(EXIT_FAILURE));
+        assert_eq!(lines.len(), 1);
+        assert_eq!(lines[0].0, Stream::Stderr);
+        assert!(
+            lines[0].1.starts_with("msh: missing/out.txt: "),
+            "{}",
+            lines[0].1
+        );
+
+        let (outcome, lines) = run("cat < missing.txt || echo recovered", &dir, &settings);
+        assert_eq!(
+            (outcome, stdout(&lines)),
+            (Outcome::Exited(0), vec!["recovered"])
+        );
+
+        // A directory which no longer exists fails in the same way
+        let (outcome, _) = run("echo lost > out.txt", &dir.join("gone"), &settings);
+        assert_eq!(outcome, Outcome::Exited(EXIT_FAILURE));
+    }
+
+    #[test]
+    fn substitutions_run_in_each_directory() {
+        let _processes = lock();
+        let settings = Settings::default();
+        for name in ["subst-a", "subst-b"] {
+            let dir = temp_dir(name);
+            std::fs::write(dir.join("name.txt"), name).unwrap();
+
+            let (outcome, lines) = run("echo $(cat name.txt) \"$(pwd)\"", &dir, &settings);
+            assert_eq!(outcome, Outcome::Exited(0));
+            assert_eq!(stdout(&lines), [format!("{} {}", name, dir.display())]);
+        }
+    }
+
+    #[test]
+    fn substitutions_split_unquoted_output() {
+        let _processes = lock();
+        let dir = temp_dir("subst-split");
+        let settings = Settings::default();
+
+        let (_, lines) = run(
+            "printf '<%s>' $(printf 'a  b') \"$(printf 'a  b')\"",
+            &dir,
+            &settings,
+        );
+        assert_eq!(stdout(&lines), ["<a><b><a  b>"]);
+        // A failing substitution still gives its output, and its stderr
+        let (outcome, lines) = run("echo x$(sh -c 'echo err >&2; exit 3')y", &dir, &settings);
+        assert_eq!(outcome, Outcome::Exited(0));
+        assert_eq!(
+            lines,
+            [
+                (Stream::Stderr, "err".to_owned()),
+                (Stream::Stdout, "xy".to_owned())
+            ]
+        );
+    }
+
+    #[test]
+    fn invalid_substitutions_are_usage_errors() {
+        let _processes = lock();
+        let dir = temp_dir("subst-invalid");
+        let settings = Settings::default();
+        let target = Target::new(dir);
+        let mut lines = Vec::new();
+
+        let outcome = substitute(
+            "echo |",
+            &target,
+            &settings,
+            &mut Watchdog::new(&settings),
+            &mut lines,
+        )
+        .unwrap_err();
+        assert_eq!(
+            outcome,
+            Outcome::Invalid(
+                "in command substitution $(echo |): missing command after |".to_owned()
+            )
+        );
+        assert_eq!(outcome.exit_code(), EXIT_USAGE);
+        assert_eq!(lines.len(), 1);
+    }
+
+    #[test]
+    fn reports_list_each_failed_directory() {
+        let result = |path: &str, outcome| DirResult {
+            path: PathBuf::from(path),
+            outcome,
+        };
+        let report = Report::new(vec![
+            result("/a", Outcome::Exited(0)),
+            result("/b", Outcome::Exited(3)),
+            result("/c", Outcome::Signaled(libc::SIGKILL)),
+            result("/d", Outcome::SpawnFailed("git: not found".to_owned())),
+        ]);
+
+        let failed: Vec<_> = report.failures().map(|r| r.path.as_path()).collect();
+        assert_eq!(failed, [Path::new("/b"), Path::new("/c"), Path::new("/d")]);
+        assert_eq!(
+            report.to_string(),
+            format!(
+                "{}\n  /b (exit 3)\n  /c (signal 9)\n  /d (spawn failed: git: not found)",
+                "1 ok, 3 failed".red()
+            )
+        );
+        assert_eq!(
+            Report::new(vec![result("/a", Outcome::Exited(0))]).to_string(),
+            "1 ok, 0 failed".green().to_string()
+        );
+    }
+
+    #[test]
+    fn stderr_is_captured_with_stdout() {
+        let _processes = lock();
+        let dir = temp_dir("stderr");
+        let (_, lines) = run("sh -c 'echo out; echo err >&2'", &dir, &Settings::default());
+        assert!(lines.contains(&(Stream::Stdout, "out".to_owned())));
+        assert!(lines.contains(&(Stream::Stderr, "err".to_owned())));
+
+        let settings = Settings {
+            stderr: StderrMode::Hide,
+            ..Settings::default()
+        };
+        let (_, lines) = run("sh -c 'echo out; echo err >&2'", &dir, &settings);
+        assert_eq!(lines, [(Stream::Stdout, "out".to_owned())]);
+    }
+
+    #[test]
+    fn stderr_modes_render_blocks() {
+        let lines = [
+            (Stream::Stderr, "e1".to_owned()),
+            (Stream::Stdout, "o1".to_owned()),
+            (Stream::Stderr, "e2".to_owned()),
+        ];
+        let (e1, e2) = ("e1".red(), "e2".red());
+        assert_eq!(
+            render(&lines, StderrMode::Interleave),
+            format!("{}\no1\n{}\n", e1, e2)
+        );
+        assert_eq!(
+            render(&lines, StderrMode::Separate),
+            format!("o1\n{}\n{}\n", e1, e2)
+        );
+        assert_eq!(render(&lines, StderrMode::Hide), "o1\n");
+    }
+
+    #[test]
+    fn results_keep_target_order() {
+        let _processes = lock();
+        let targets: Vec<Target> = (0..4)
+            .map(|i| Target::new(temp_dir(&format!("order-{}", i))))
+            .collect();
+        // The first directories finish last
+        for (i, target) in targets.iter().enumerate() {
+            let delay = format!("0.{}", 3 - i);
+            std::fs::write(target.path.join("delay"), delay).unwrap();
+        }
+        let list = parser::parse_substitution("sleep $(cat delay)").unwrap();
+        let settings = Settings {
+            jobs: Jobs(4),
+            ..Settings::default()
+        };
+
+        let results = run_all(&list, &targets, &settings).unwrap();
+        let paths: Vec<_> = results.iter().map(|r| &r.path).collect();
+        assert_eq!(paths, targets.iter().map(|t| &t.path).collect::<Vec<_>>());
+        assert!(results.iter().all(|r| r.outcome.success()));
+    }
+
+    #[test]
+    fn watchdog_terminates_then_kills_after_the_timeout() {
+        let _processes = lock();
+        let settings = Settings {
+            timeout: Timeout(Some(Duration::from_millis(10))),
+            grace: Grace(Duration::from_millis(100)),
+            ..Settings::default()
+        };
+
+        let mut child = sleep();
+        let mut watchdog = Watchdog::new(&settings);
+        thread::sleep(Duration::from_millis(20));
+        watchdog.check(&child);
+        assert!(watchdog.timed_out());
+        assert_eq!(child.wait().unwrap().signal(), Some(libc::SIGTERM));
+
+        let mut stubborn = Command::new("sh")
+            .args(["-c", "trap '' TERM; sleep 10"])
+            .process_group(0)
+            .spawn()
+            .unwrap();
+        let mut watchdog = Watchdog::new(&settings);
+        let status = loop {
+            watchdog.check(&stubborn);
+            if let Some(status) = stubborn.try_wait().unwrap() {
+                break status;
+            }
+            thread::sleep(Duration::from_millis(10));
+        };
+        assert_eq!(status.signal(), Some(libc::SIGKILL));
+    }
+
+    fn expand_line(line: &str, dir: &Path, nomatch: NoMatch) -> io::Result<Vec<String>> {
+        let words: Vec<Word> = words::split(line)
+            .unwrap()
+            .into_iter()
+            .map(|token| match token {
+                Token::Word(word) => word,
+                Token::Operator(op) => panic!("unexpected {:?}", op),
+            })
+            .collect();
+        expand_args(&words, dir, nomatch)
+    }
+
+    #[test]
+    fn globs_expand_in_the_directory() {
+        let dir = temp_dir("glob");
+        for file in ["b.rs", "a.rs", ".hidden.rs", "notes.txt"] {
+            std::fs::write(dir.join(file), "").unwrap();
+        }
+        let expand = |line| expand_line(line, &dir, NoMatch::Literal).unwrap();
+
+        assert_eq!(expand("ls *.rs"), ["ls", "a.rs", "b.rs"]);
+        assert_eq!(
+            expand("ls .*.rs ./*.txt"),
+            ["ls", ".hidden.rs", "./notes.txt"]
+        );
+        assert_eq!(expand("ls '*.rs' \\*.rs"), ["ls", "*.rs", "*.rs"]);
+        assert_eq!(
+            expand(&format!("ls {}/?.rs", dir.display())),
+            [
+                "ls".to_owned(),
+                format!("{}/a.rs", dir.display()),
+                format!("{}/b.rs", dir.display())
+            ]
+        );
+    }
+
+    #[test]
+    fn unmatched_globs_follow_nomatch() {
+        let dir = temp_dir("nomatch");
+        std::fs::write(dir.join("a.rs"), "").unwrap();
+
+        assert_eq!(
+            expand_line("ls *.md *.rs", &dir, NoMatch::Literal).unwrap(),
+            ["ls", "*.md", "a.rs"]
+        );
+        assert_eq!(
+            expand_line("ls *.md *.rs", &dir, NoMatch::Null).unwrap(),
+            ["ls", "a.rs"]
+        );
+        assert_eq!(
+            expand_line("*.md", &dir, NoMatch::Null)
+                .unwrap_err()
+                .to_string(),
+            "no command left after glob expansion"
+        );
+        assert_eq!(
+            expand_line("ls *.md *.rs", &dir, NoMatch::Fail)
+                .unwrap_err()
+                .to_string(),
+            "no match for *.md"
+        );
+        assert_eq!(
+            expand_line("ls *.rs", &dir, NoMatch::Fail).unwrap(),
+            ["ls", "a.rs"]
+        );
+    }
+}
@@ -3,6 +3,9 @@
 // #![warn(clippy::cargo)]
 #![allow(clippy::default_trait_access)]
 #![allow(clippy::multiple_crate_versions)]
+#![allow(clippy::redundant_pub_crate)]
+#![allow(clippy::uninlined_format_args)]
+#![allow(clippy::unnecessary_semicolon)]
 
 #[macro_use]
 extern crate clap;
@@ -11,12 +14,22 @@ extern crate derive_builder;
 #[macro_use]
 extern crate log;
 
-use env_logger;
-
+mod config;
 mod context;
-// mod exec;
+mod discover;
+mod exec;
 mod parser;
+mod registry;
 mod repl;
+mod selector;
+mod settings;
+mod state;
+mod words;
+mod workspace;
+
+use std::process;
+
+use crate::config::ColorMode;
 
 fn main() {
     env_logger::init();
@@ -25,21 +38,34 @@ fn main() {
         Ok(c) => c,
         Err(s) => {
             eprintln!("{}", s);
-            return;
+            process::exit(exec::EXIT_USAGE);
+        }
+    };
+
+    match cfg.color() {
+        ColorMode::Auto if atty::isnt(atty::Stream::Stdout) => {
+            colored::control::set_override(false);
         }
+        ColorMode::Auto => {}
+        ColorMode::Always => colored::control::set_override(true),
+        ColorMode::Never => colored::control::set_override(false),
     };
 
-    if atty::isnt(atty::Stream::Stdin) {
-        eprintln!("msh: error: cannot accept piped input");
-        return;
-    }
+    let result =
+        if cfg.command().is_some() || cfg.script().is_some() || atty::isnt(atty::Stream::Stdin) {
+            repl::run_script(&cfg)
+        } else {
+            repl::repl_loop(&cfg)
+        };
 
-    match repl::repl_loop(&cfg) {
-        Ok(_) => {
-            info!("Loop is exiting");
+    match result {
+        Ok(code) => {
+            info!("Exiting with code {}", code);
+            process::exit(code);
         }
         Err(s) => {
-            error!("{}", s);
+            eprintln!("msh: {}", s);
+            process::exit(exec::EXIT_FAILURE);
         }
     };
 }
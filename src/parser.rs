@@ -3,21 +3,68 @@
 #![warn(clippy::cargo)]
 #![allow(clippy::default_trait_access)]
 #![allow(clippy::multiple_crate_versions)]
-
+#![allow(clippy::redundant_pub_crate)]
+#![allow(clippy::uninlined_format_args)]
+#![allow(clippy::unnecessary_semicolon)]
 
 use clap::{
-    App, AppSettings, Arg,
-    ErrorKind::{HelpDisplayed, UnknownArgument, UnrecognizedSubcommand},
+    App, AppSettings, Arg, ArgMatches,
+    ErrorKind::{
+        ArgumentConflict, HelpDisplayed, MissingRequiredArgument, UnknownArgument,
+        UnrecognizedSubcommand, ValueValidation,
+    },
     SubCommand,
 };
 
 use std::borrow::ToOwned;
 use std::ffi::OsString;
-use std::iter::Peekable;
+use std::mem;
 
-use crate::context::{self, get_home_dir, Context, MshConfig, MshConfigBuilder};
+use crate::config;
+use crate::context::{get_home_dir, Context, MshConfig, MshConfigBuilder, Predicate};
+use crate::exec::{CommandList, Connector, Exclusion, Job, Pipeline, Redirect, SimpleCommand};
 use crate::repl::Action;
+use crate::selector;
+use crate::settings::{self, Jobs};
+use crate::words::{self, Operator, Token};
+
+fn group_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("GROUP")
+        .short("g")
+        .long("group")
+        .takes_value(true)
+        .help("Acts on GROUP instead of the active group")
+}
+
+fn values(args: &ArgMatches, name: &str) -> Vec<String> {
+    args.values_of(name)
+        .map_or_else(Vec::new, |v| v.map(ToOwned::to_owned).collect())
+}
+
+const PREFIX_HELP: &str = "PREFIXES:
+    Any other line runs as a command in every directory of the active group. Prefixes
+    before the command change how it runs:
+
+    :NAME=VALUE     Overrides a setting for this command, e.g. `:jobs=1 make`
+    @SELECTOR       Runs in the directories matched by a selector, e.g. `@web,!legacy ls`
+    @{DIR,...}      Runs in these directories instead, without registering them
+    !EXCLUSION      Leaves out a path (if it contains /, starts with . or is a glob), or a
+                    group, tag or directory name, e.g. `!./legacy !slow cargo build`";
 
+const EXIT_CODES_HELP: &str = "EXIT CODES:
+    The exit status of a command is the highest of these across its directories:
+
+    0      Success
+    1      The command, or a builtin, failed
+    2      The line could not be parsed, or selected no directories
+    124    The command timed out
+    127    The command could not be started
+    130    The command was interrupted with Ctrl-C
+
+    msh exits with the status of the last command, or of a builtin which failed after it,
+    unless given a code from 0 to 255 with `exit`.";
+
+#[allow(clippy::too_many_lines)]
 fn get_builtin<I, T>(args: I) -> Option<Action>
 where
     I: IntoIterator<Item = T>,
@@ -25,14 +72,30 @@ where
 {
     let mut builtins = App::new("builtins")
         .usage("[SUBCOMMAND]")
+        .after_help(PREFIX_HELP)
         .settings(&[AppSettings::NoBinaryName, AppSettings::ColorNever])
         .subcommand(
             SubCommand::with_name("exit")
                 .visible_alias("quit")
-                .about("Terminates the shell"),
+                .about("Terminates the shell")
+                .setting(AppSettings::AllowLeadingHyphen)
+                .arg(
+                    Arg::with_name("CODE")
+                        .validator(|v| {
+                            v.parse::<u8>()
+                                .map(|_| ())
+                                .map_err(|_| format!("{} is not an exit code from 0 to 255", v))
+                        })
+                        .help("The exit code [default: the exit status of the last command]"),
+                )
+                .after_help(EXIT_CODES_HELP),
         )
         .subcommand(SubCommand::with_name("help").about("Displays this help message"))
-        .subcommand(SubCommand::with_name("dirs").about("Prints all registered directories"))
+        .subcommand(
+            SubCommand::with_name("dirs")
+                .about("Prints all registered directories")
+                .arg(group_arg()),
+        )
         .subcommand(
             SubCommand::with_name("cd")
                 .about("Change the current working directory")
@@ -55,29 +118,205 @@ where
                         .help("Deletes NAME from the environment"),
                 ),
         )
+        .subcommand(
+            SubCommand::with_name("set")
+                .about("Change a shell setting, or print all settings when given no arguments")
+                .after_help(settings::HELP)
+                .arg(Arg::with_name("NAME").requires("VALUE"))
+                .arg(Arg::with_name("VALUE")),
+        )
+        .subcommand(
+            SubCommand::with_name("jobs")
+                .about("Set how many directories run at once, or print the current limit")
+                .arg(Arg::with_name("N").help("A number, or auto; 1 runs serially")),
+        )
         .subcommand(
             SubCommand::with_name("register")
                 .about("Add directories to the registry")
                 .visible_alias("reg")
-                .arg(Arg::with_name("DIRS").required(true).min_values(1)),
+                .arg(group_arg().help("Registers into GROUP, creating it if needed"))
+                .arg(
+                    Arg::with_name("DIRS")
+                        .required(true)
+                        .min_values(1)
+                        .help("Directories, or glob patterns such as 'services/*'"),
+                ),
         )
         .subcommand(
             SubCommand::with_name("unregister")
                 .about("Remove directories from the registry")
                 .visible_alias("unreg")
+                .arg(group_arg())
                 .arg(Arg::with_name("DIRS").required(true).min_values(1)),
         )
         .subcommand(
             SubCommand::with_name("register-file")
                 .about("Add all directories from FILE to the registry")
                 .visible_alias("regfile")
-                .arg(Arg::with_name("FILE").required(true)),
+                .arg(group_arg().help("Registers into GROUP, creating it if needed"))
+                .arg(Arg::with_name("FILE").required(true))
+                .after_help(
+                    "FILE is either a whitespace-separated list of directories, or TOML with \
+                     one [[dir]] table per directory, holding its path and optionally a name, \
+                     tags, groups and env. Relative paths are resolved against the directory \
+                     of FILE, and # starts a comment in both formats.",
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("discover")
+                .about("Register every directory below ROOTS holding a marker file")
+                .arg(group_arg().help("Registers into GROUP, creating it if needed"))
+                .arg(
+                    Arg::with_name("MARKER")
+                        .short("m")
+                        .long("marker")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .help(
+                            "A file or directory marking a directory to register \
+                             [default: .git, Cargo.toml, package.json]",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("DEPTH")
+                        .short("d")
+                        .long("depth")
+                        .takes_value(true)
+                        .validator(|v| v.parse::<usize>().map(|_| ()).map_err(|e| e.to_string()))
+                        .help("Searches at most DEPTH levels below each root"),
+                )
+                .arg(
+                    Arg::with_name("IGNORE")
+                        .short("i")
+                        .long("ignore")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .help("Skips directories whose name or relative path matches this glob"),
+                )
+                .arg(Arg::with_name("ROOTS").multiple(true).default_value("."))
+                .after_help("Hidden directories are never searched."),
+        )
+        .subcommand(
+            SubCommand::with_name("register-workspace")
+                .about("Register every member of the Cargo or npm workspace at PATH")
+                .visible_alias("regws")
+                .arg(group_arg().help("Registers into GROUP, creating it if needed"))
+                .arg(
+                    Arg::with_name("TAG")
+                        .short("t")
+                        .long("tag")
+                        .help("Tags each member with the workspace name"),
+                )
+                .arg(Arg::with_name("PATH").default_value("."))
+                .after_help(
+                    "Members are read from [workspace] members and exclude in Cargo.toml, or \
+                     else from workspaces in package.json. The workspace name is the name of \
+                     the directory at PATH.",
+                ),
         )
         .subcommand(
             SubCommand::with_name("clear-register")
                 .about("Remove all directories from the registry")
                 .visible_alias("clreg")
+                .arg(group_arg())
                 .arg(Arg::with_name("DIRS").multiple(true)),
+        )
+        .subcommand(
+            SubCommand::with_name("filter")
+                .about("Keep only the registered directories where CMD succeeds")
+                .setting(AppSettings::TrailingVarArg)
+                .arg(group_arg())
+                .arg(
+                    Arg::with_name("EXISTS")
+                        .short("e")
+                        .long("exists")
+                        .takes_value(true)
+                        .value_name("FILE")
+                        .conflicts_with("CMD")
+                        .help("Keeps directories containing FILE, instead of running CMD"),
+                )
+                .arg(
+                    Arg::with_name("INVERT")
+                        .short("v")
+                        .long("invert")
+                        .help("Keeps the directories which fail instead"),
+                )
+                .arg(
+                    Arg::with_name("DRY_RUN")
+                        .short("n")
+                        .long("dry-run")
+                        .help("Prints what would be kept without changing the registry"),
+                )
+                .arg(
+                    Arg::with_name("CMD")
+                        .multiple(true)
+                        .required_unless("EXISTS"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("group")
+                .about("Manage named groups of registered directories")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    SubCommand::with_name("create")
+                        .about("Create empty groups")
+                        .arg(Arg::with_name("NAMES").required(true).min_values(1)),
+                )
+                .subcommand(
+                    SubCommand::with_name("delete")
+                        .about("Delete a group and its registry")
+                        .arg(Arg::with_name("NAME").required(true)),
+                )
+                .subcommand(
+                    SubCommand::with_name("use")
+                        .about("Make a group the active group")
+                        .arg(Arg::with_name("NAME").required(true)),
+                )
+                .subcommand(
+                    SubCommand::with_name("list").about("List all groups, marking the active one"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("tag")
+                .about("Manage tags on registered directories")
+                .after_help(
+                    "Run a command in tagged directories with a selector, e.g. `@rust,!legacy \
+                     cargo fmt`.\nIn a selector, `,` means and, `+` means or, `!` means not, and \
+                     each name matches a group, a tag or a directory name.",
+                )
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    SubCommand::with_name("add")
+                        .about("Attach TAG to registered directories")
+                        .arg(Arg::with_name("TAG").required(true))
+                        .arg(Arg::with_name("DIRS").required(true).min_values(1)),
+                )
+                .subcommand(
+                    SubCommand::with_name("remove")
+                        .about("Detach TAG from directories")
+                        .arg(Arg::with_name("TAG").required(true))
+                        .arg(Arg::with_name("DIRS").required(true).min_values(1)),
+                )
+                .subcommand(
+                    SubCommand::with_name("list").about("List all tags and their directories"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("registry")
+                .about("Import and export the registry")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    SubCommand::with_name("save")
+                        .about(
+                            "Write the registered directories to FILE, for use with register-file",
+                        )
+                        .arg(group_arg().help(
+                            "Writes only the directories of GROUP, rather than of every group",
+                        ))
+                        .arg(Arg::with_name("FILE").required(true)),
+                ),
         );
 
     match builtins.get_matches_from_safe_borrow(args) {
@@ -90,14 +329,21 @@ where
                 println!("{}", e.message);
                 Some(Action::Loop)
             }
+            ValueValidation | MissingRequiredArgument | ArgumentConflict => {
+                Some(Action::Error(e.message))
+            }
             _ => {
                 error!("Builtin parsing error: {:?}", e);
                 Some(Action::Loop)
             }
         },
         Ok(x) => match x.subcommand() {
-            ("dirs", _) => Some(Action::Dump),
-            ("exit", _) => Some(Action::Exit(None)),
+            ("dirs", Some(args)) => {
+                Some(Action::Dump(args.value_of("GROUP").map(ToOwned::to_owned)))
+            }
+            ("exit", Some(args)) => {
+                Some(Action::Exit(args.value_of("CODE").map(ToOwned::to_owned)))
+            }
             ("cd", Some(args)) => Some(Action::ChDir(args.value_of("DIR").unwrap().to_owned())),
             ("echo", Some(args)) => {
                 let joined = args
@@ -118,6 +364,22 @@ where
                 };
                 Some(action)
             }
+            ("set", Some(args)) => match (args.value_of("NAME"), args.value_of("VALUE")) {
+                (Some(name), Some(value)) => Some(Action::Set {
+                    name: name.to_owned(),
+                    value: value.to_owned(),
+                }),
+                _ => Some(Action::ShowSettings),
+            },
+            ("jobs", Some(args)) => {
+                Some(
+                    args.value_of("N")
+                        .map_or(Action::ShowJobs, |n| Action::Set {
+                            name: "jobs".to_owned(),
+                            value: n.to_owned(),
+                        }),
+                )
+            }
             ("help", _) => {
                 builtins
                     .print_long_help()
@@ -125,31 +387,73 @@ where
                 println!();
                 Some(Action::Loop)
             }
-            ("register", Some(args)) => Some(Action::Register(
-                args.values_of("DIRS")
-                    .unwrap()
-                    .map(ToOwned::to_owned)
-                    .collect(),
-            )),
-            ("unregister", Some(args)) => Some(Action::Unregister(
-                args.values_of("DIRS")
-                    .unwrap()
-                    .map(ToOwned::to_owned)
-                    .collect(),
-            )),
-            ("register-file", Some(args)) => Some(Action::RegisterFile(
-                args.value_of("FILE").unwrap().to_owned(),
-            )),
-            ("clear-register", Some(args)) => Some(Action::ClearRegistry({
-                if args.is_present("DIRS") {
-                    args.values_of("DIRS")
-                        .unwrap()
-                        .map(ToOwned::to_owned)
-                        .collect()
-                } else {
-                    Vec::new()
+            ("register", Some(args)) => Some(Action::Register {
+                group: args.value_of("GROUP").map(ToOwned::to_owned),
+                dirs: values(args, "DIRS"),
+            }),
+            ("unregister", Some(args)) => Some(Action::Unregister {
+                group: args.value_of("GROUP").map(ToOwned::to_owned),
+                dirs: values(args, "DIRS"),
+            }),
+            ("discover", Some(args)) => Some(Action::Discover {
+                group: args.value_of("GROUP").map(ToOwned::to_owned),
+                roots: values(args, "ROOTS"),
+                markers: values(args, "MARKER"),
+                depth: args.value_of("DEPTH").and_then(|d| d.parse().ok()),
+                ignore: values(args, "IGNORE"),
+            }),
+            ("filter", Some(args)) => Some(Action::Filter {
+                group: args.value_of("GROUP").map(ToOwned::to_owned),
+                predicate: args.value_of("EXISTS").map_or_else(
+                    || Predicate::Command(values(args, "CMD")),
+                    |file| Predicate::Exists(file.to_owned()),
+                ),
+                invert: args.is_present("INVERT"),
+                dry_run: args.is_present("DRY_RUN"),
+            }),
+            ("register-workspace", Some(args)) => Some(Action::RegisterWorkspace {
+                group: args.value_of("GROUP").map(ToOwned::to_owned),
+                path: args.value_of("PATH").unwrap().to_owned(),
+                tag: args.is_present("TAG"),
+            }),
+            ("register-file", Some(args)) => Some(Action::RegisterFile {
+                group: args.value_of("GROUP").map(ToOwned::to_owned),
+                file: args.value_of("FILE").unwrap().to_owned(),
+            }),
+            ("clear-register", Some(args)) => Some(Action::ClearRegistry {
+                group: args.value_of("GROUP").map(ToOwned::to_owned),
+                dirs: values(args, "DIRS"),
+            }),
+            ("group", Some(args)) => match args.subcommand() {
+                ("create", Some(args)) => Some(Action::CreateGroups(values(args, "NAMES"))),
+                ("delete", Some(args)) => Some(Action::DeleteGroup(
+                    args.value_of("NAME").unwrap().to_owned(),
+                )),
+                ("use", Some(args)) => {
+                    Some(Action::UseGroup(args.value_of("NAME").unwrap().to_owned()))
                 }
-            })),
+                ("list", _) => Some(Action::ListGroups),
+                _ => unreachable!(),
+            },
+            ("tag", Some(args)) => match args.subcommand() {
+                ("add", Some(args)) => Some(Action::Tag {
+                    tag: args.value_of("TAG").unwrap().to_owned(),
+                    dirs: values(args, "DIRS"),
+                }),
+                ("remove", Some(args)) => Some(Action::Untag {
+                    tag: args.value_of("TAG").unwrap().to_owned(),
+                    dirs: values(args, "DIRS"),
+                }),
+                ("list", _) => Some(Action::ListTags),
+                _ => unreachable!(),
+            },
+            ("registry", Some(args)) => match args.subcommand() {
+                ("save", Some(args)) => Some(Action::SaveRegistry {
+                    group: args.value_of("GROUP").map(ToOwned::to_owned),
+                    file: args.value_of("FILE").unwrap().to_owned(),
+                }),
+                _ => unreachable!(),
+            },
             _ => unreachable!(),
         },
     }
@@ -170,231 +474,465 @@ pub(crate) fn handle_line(ctx: &mut Context, line: &str) -> Action {
         return Action::Loop;
     };
 
-    let args = match expand_line(&full_line) {
-        Ok(v) => v,
-        Err(e) => {
-            eprintln!("{}", e);
-            return Action::Loop;
-        }
+    let tokens = match words::split(&full_line) {
+        Ok(tokens) => tokens,
+        Err(e) => return Action::Error(format!("msh: {}", e)),
     };
 
-    get_builtin(&args).unwrap_or_else(|| {
-        debug!("reading line into process executor: {}", &full_line);
-        Action::Execute(args.iter().map(ToString::to_string).collect())
-    })
+    let job = match parse_job(tokens) {
+        Ok(j) => j,
+        Err(e) => return Action::Error(format!("msh: {}", e)),
+    };
+
+    // Lists, pipelines and redirections always run in the directories, so that `echo` can
+    // feed the commands after it, or write a file in each directory
+    if let Some(args) = job.list.simple_args().filter(|_| job.is_plain()) {
+        if let Some(action) = get_builtin(&args) {
+            return action;
+        }
+    }
+
+    debug!("reading line into process executor: {}", &full_line);
+    Action::Execute(job)
     // unimplemented!()
 }
 
+/// Builds a job from the tokens of a line, splitting off any leading `:NAME=VALUE` setting
+/// overrides, `@SELECTOR` or `@{DIR,...}` target selection and `!EXCLUSION`s from the
+/// pipeline itself.
+fn parse_job(tokens: Vec<Token>) -> Result<Job, String> {
+    let mut overrides = Vec::new();
+    let mut selector = None;
+    let mut paths = Vec::new();
+    let mut excludes = Vec::new();
+    let mut tokens = tokens.into_iter().peekable();
+
+    while let Some(Token::Word(word)) = tokens.peek() {
+        let word = word.text();
+        if let Some((name, value)) = word.strip_prefix(':').and_then(|w| w.split_once('=')) {
+            trace!("Setting override found: {} = {}", name, value);
+            overrides.push((name.to_owned(), value.to_owned()));
+        } else if let Some(set) = word.strip_prefix("@{").and_then(|w| w.strip_suffix('}')) {
+            trace!("Ad-hoc target set found: {}", set);
+            if !paths.is_empty() {
+                return Err("only one @{DIR,...} target set may be given per command".to_owned());
+            }
+            paths = set
+                .split(',')
+                .filter(|p| !p.is_empty())
+                .map(ToOwned::to_owned)
+                .collect();
+            if paths.is_empty() {
+                return Err("empty @{DIR,...} target set".to_owned());
+            }
+        } else if let Some(excluded) = word.strip_prefix('!').filter(|w| !w.is_empty()) {
+            trace!("Exclusion found: {}", excluded);
+            excludes.push(Exclusion::parse(excluded));
+        } else if let Some(expr) = word.strip_prefix('@') {
+            trace!("Target selector found: {}", expr);
+            if selector.replace(selector::parse(expr)?).is_some() {
+                return Err("only one @SELECTOR may be given per command".to_owned());
+            }
+        } else {
+            break;
+        }
+        tokens.next();
+    }
+
+    if tokens.peek().is_none() {
+        return Err("missing command after prefixes".to_owned());
+    }
+    if selector.is_some() && !paths.is_empty() {
+        return Err("@SELECTOR and @{DIR,...} cannot be combined".to_owned());
+    }
+
+    Ok(Job {
+        overrides,
+        selector,
+        paths,
+        excludes,
+        list: parse_list(tokens)?,
+    })
+}
+
+/// Splits tokens into the pipelines of a command list at each `;`, `&&` and `||`. A list may
+/// end with `;`.
+fn parse_list<I>(tokens: I) -> Result<CommandList, String>
+where
+    I: Iterator<Item = Token>,
+{
+    let mut pipelines = Vec::new();
+    let mut connector = Connector::Then;
+    let mut last_op = None;
+    let mut current = Vec::new();
+
+    for token in tokens {
+        let (next, op) = match token {
+            Token::Operator(op @ Operator::Sequence) => (Connector::Then, op),
+            Token::Operator(op @ Operator::And) => (Connector::And, op),
+            Token::Operator(op @ Operator::Or) => (Connector::Or, op),
+            other => {
+                current.push(other);
+                continue;
+            }
+        };
+        if current.is_empty() {
+            return Err(format!("missing command before {}", op.as_str()));
+        }
+        pipelines.push((
+            connector,
+            parse_pipeline(mem::take(&mut current).into_iter())?,
+        ));
+        connector = next;
+        last_op = Some(op);
+    }
+
+    if current.is_empty() {
+        if let Some(op) = last_op.filter(|&op| op != Operator::Sequence) {
+            return Err(format!("missing command after {}", op.as_str()));
+        }
+    } else {
+        pipelines.push((connector, parse_pipeline(current.into_iter())?));
+    }
+
+    let mut pipelines = pipelines.into_iter();
+    let (_, first) = pipelines.next().ok_or("missing command")?;
+    Ok(CommandList {
+        first,
+        rest: pipelines.collect(),
+    })
+}
+
+/// Parses the command of a `$(...)` substitution, which may be any command list.
+pub(crate) fn parse_substitution(source: &str) -> Result<CommandList, String> {
+    parse_list(words::split(source)?.into_iter())
+}
+
+/// Splits tokens into the commands of a pipeline at each `|`, taking the file following
+/// each redirection operator.
+fn parse_pipeline<I>(mut tokens: I) -> Result<Pipeline, String>
+where
+    I: Iterator<Item = Token>,
+{
+    let mut commands = Vec::new();
+    let mut args = Vec::new();
+    let mut redirects = Vec::new();
+
+    while let Some(token) = tokens.next() {
+        let op = match token {
+            Token::Word(word) => {
+                for source in word.substitutions() {
+                    parse_substitution(source)
+                        .map_err(|e| format!("in command substitution $({}): {}", source, e))?;
+                }
+                args.push(word);
+                continue;
+            }
+            Token::Operator(Operator::Pipe) => {
+                if args.is_empty() {
+                    return Err("missing command before |".to_owned());
+                }
+                commands.push(SimpleCommand {
+                    args: mem::take(&mut args),
+                    redirects: mem::take(&mut redirects),
+                });
+                continue;
+            }
+            Token::Operator(Operator::ErrorToOutput) => {
+                redirects.push(Redirect::ErrorToOutput);
+                continue;
+            }
+            Token::Operator(op) => op,
+        };
+
+        let Some(Token::Word(file)) = tokens.next() else {
+            return Err(format!("missing file after {}", op.as_str()));
+        };
+        if file.substitutions().next().is_some() {
+            return Err("command substitution cannot name a redirected file".to_owned());
+        }
+        let path = file.text();
+        redirects.push(match op {
+            Operator::Input => Redirect::Input(path),
+            Operator::Output => Redirect::Output {
+                path,
+                append: false,
+            },
+            Operator::Append => Redirect::Output { path, append: true },
+            Operator::Error => Redirect::Error {
+                path,
+                append: false,
+            },
+            Operator::ErrorAppend => Redirect::Error { path, append: true },
+            _ => unreachable!(),
+        });
+    }
+
+    if args.is_empty() {
+        return Err(if commands.is_empty() {
+            "missing command".to_owned()
+        } else {
+            "missing command after |".to_owned()
+        });
+    }
+    commands.push(SimpleCommand { args, redirects });
+    Ok(Pipeline { commands })
+}
+
 pub(crate) fn parse_external_args() -> Result<MshConfig, String> {
     let matches = app_from_crate!()
+        .after_help(EXIT_CODES_HELP)
         .arg(
             Arg::with_name("registry")
                 .short("r")
                 .long("registry")
                 .value_name("FILE")
                 .help("pre-loads registered directories")
-                .long_help("A whitespace-separated list of directories to automatically register."),
+                .long_help(
+                    "A registry file of directories to automatically register: either a \
+                     whitespace-separated list, or [[dir]] entries in TOML.",
+                ),
+        )
+        .arg(
+            Arg::with_name("jobs")
+                .short("j")
+                .long("jobs")
+                .value_name("N")
+                .help("limits how many directories run at once")
+                .long_help(
+                    "The number of directories to run in at once, or auto. 1 runs serially.",
+                ),
+        )
+        .arg(
+            Arg::with_name("fresh")
+                .long("fresh")
+                .help("starts with an empty registry, and does not save it on exit"),
+        )
+        .arg(
+            Arg::with_name("norc")
+                .long("norc")
+                .help("does not run ~/.mshrc when the shell starts"),
+        )
+        .arg(
+            Arg::with_name("config")
+                .long("config")
+                .value_name("PATH")
+                .help("reads the configuration file at PATH")
+                .long_help(
+                    "The configuration file to read, instead of config.toml in the msh \
+                     directory of the user configuration directory, e.g. ~/.config/msh.",
+                ),
+        )
+        .arg(
+            Arg::with_name("command")
+                .short("c")
+                .value_name("COMMANDS")
+                .conflicts_with("SCRIPT")
+                .help("runs COMMANDS, one per line, instead of starting the shell"),
+        )
+        .arg(
+            Arg::with_name("SCRIPT")
+                .help("runs the commands in this file instead of starting the shell")
+                .long_help(
+                    "A file of commands to run, one per line, instead of starting the shell. \
+                     Commands are also read from standard input when it is not a terminal. \
+                     These runs restore the saved registry, but do not save changes to it.",
+                ),
         )
         .get_matches();
 
+    let (user, config_file) = config::load(matches.value_of("config"))?;
     let mut cfg_build = MshConfigBuilder::default();
-
-    if let Some(x) = matches.value_of("registry") {
-        match context::read_registry_file(x) {
-            Ok(v) => {
-                cfg_build.preload_dirs(v);
-            }
-            Err(e) => {
-                warn!("Could not read registry file \"{}\" -> error: {}", x, e);
-            }
-        };
+    cfg_build.fresh(matches.is_present("fresh"));
+    cfg_build.norc(matches.is_present("norc"));
+    cfg_build.prompt(user.prompt.clone());
+    cfg_build.edit_mode(user.edit_mode);
+    cfg_build.color(user.color);
+
+    // Settings from the command line are applied last, overriding the configuration file
+    let mut settings = user.settings();
+
+    if let Some(x) = matches.value_of("jobs") {
+        x.parse::<Jobs>()?;
+        settings.push(("jobs".to_owned(), x.to_owned()));
     };
+    cfg_build.settings(settings);
+
+    let registry_file = matches
+        .value_of("registry")
+        .map(ToOwned::to_owned)
+        .or_else(|| {
+            user.registry_file(config_file.as_deref())
+                .map(|p| p.to_string_lossy().into_owned())
+        });
+    cfg_build.registry_file(registry_file);
+    cfg_build.command(matches.value_of("command").map(ToOwned::to_owned));
+    cfg_build.script(matches.value_of("SCRIPT").map(ToOwned::to_owned));
     cfg_build.build()
 }
 
-pub(crate) fn expand_line(line: &str) -> Result<Vec<String>, &'static str> {
-    let mut bytes = line.bytes().enumerate().peekable();
-    let mut args = Vec::new();
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    while let Some(&(_, b)) = bytes.peek() {
-        // trace!("Line expansion: index {}, char {}", i+1, b as char);
-        match b {
-            b' ' | b'\t' => {
-                bytes.next(); // Move peek to next byte, skip current
-            }
-            _ => {
-                if let Some(v) = arg(line, &mut bytes)? {
-                    args.push(v)
-                }
-            }
+    #[test]
+    fn exit_takes_codes_from_0_to_255() {
+        assert_eq!(
+            get_builtin(["exit", "0"]),
+            Some(Action::Exit(Some("0".to_owned())))
+        );
+        assert_eq!(
+            get_builtin(["quit", "255"]),
+            Some(Action::Exit(Some("255".to_owned())))
+        );
+        assert_eq!(get_builtin(["exit"]), Some(Action::Exit(None)));
+    }
+
+    #[test]
+    fn exit_rejects_other_codes() {
+        for code in ["256", "-1", "-x", "one"] {
+            assert!(
+                matches!(get_builtin(["exit", code]), Some(Action::Error(_))),
+                "exit {} was not rejected",
+                code
+            );
         }
     }
 
-    // Ok(args)
-    let expanded = args.drain(..).map(expand_var).collect();
-    Ok(expanded)
-}
+    #[test]
+    fn substitutions_parse_as_command_lists() {
+        let list = parse_substitution("git rev-parse HEAD").unwrap();
+        assert_eq!(
+            list.simple_args(),
+            Some(vec![
+                "git".to_owned(),
+                "rev-parse".to_owned(),
+                "HEAD".to_owned()
+            ])
+        );
 
-fn expand_var(s: &str) -> String {
-    debug!("Checking for var expansion in str: {}", s);
-    if s.starts_with('\'') {
-        return s.into();
+        let list = parse_substitution("cd .. && ls | head -n 1; pwd").unwrap();
+        assert_eq!(list.rest.len(), 2);
+        assert_eq!(list.rest[0].1.commands.len(), 2);
+        assert!(parse_substitution("echo $(basename $(pwd))").is_ok());
     }
 
-    let mut buf = String::with_capacity(s.len() * 2);
-    let mut bytes = s.bytes().peekable();
-
-    while let Some(&b) = bytes.peek() {
-        // trace!("Line expansion: index {}, char {}", i+1, b as char);
-        match b {
-            b'\\' => {
-                // buf.push(b as char);
-                // Treat the next char as normal
-                bytes.next(); // Skip backslash
-                match bytes.next() {
-                    // Move peek to next byte, but consume the current one.
-                    Some(b) => {
-                        buf.push(b as char);
-                    }
-                    // Cannot be none, previous parser would have consumed the next char.
-                    None => unreachable!(),
-                }
-            }
-            b'$' => {
-                bytes.next(); // Skip the leading '$'
-                let mut var_name = String::new();
-                while let Some(&b) = bytes.peek() {
-                    match b {
-                        b'a'...b'z' | b'A'...b'Z' | b'_' | b'0'...b'9' => {
-                            var_name.push(b as char);
-                            bytes.next(); // Move peek to next byte
-                        }
-                        _ => {
-                            break;
-                        }
-                    }
-                }
+    #[test]
+    fn substitutions_report_parse_errors() {
+        let error = |source| parse_substitution(source).unwrap_err();
+        assert_eq!(error(""), "missing command");
+        assert_eq!(error("echo |"), "missing command after |");
+        assert_eq!(error("echo 'open"), "Unterminated single quote");
+        assert_eq!(
+            error("echo $(true &&)"),
+            "in command substitution $(true &&): missing command after &&"
+        );
+        assert_eq!(
+            error("cat < $(ls)"),
+            "command substitution cannot name a redirected file"
+        );
+    }
 
-                let maybe_expansion =
-                    std::env::var(&var_name).unwrap_or_else(|_| format!("${}", &var_name));
-                debug!("Expanded ${} to {}", var_name, maybe_expansion);
-                buf.push_str(&maybe_expansion);
-            }
-            b'~' => {
-                let home = get_home_dir();
-                debug!("Expanding ~ to {}", home);
-                buf.push_str(home);
-                bytes.next(); // Move peek to next byte, skip the ~
-            }
-            _ => {
-                buf.push(b as char);
-                bytes.next(); // Move peek to next byte, take the char.
-            }
-        }
+    fn parse(line: &str) -> Result<Job, String> {
+        parse_job(words::split(line)?)
     }
 
-    debug!("Finished var expansion: {}", buf);
-    buf
-}
+    #[test]
+    fn jobs_take_prefixes_before_the_command() {
+        let job = parse("!./legacy !web @rust+go :jobs=2 :timeout=10 git pull").unwrap();
+        assert_eq!(
+            job.excludes,
+            [
+                Exclusion::Path("./legacy".to_owned()),
+                Exclusion::Name("web".to_owned())
+            ]
+        );
+        assert_eq!(job.selector, Some(selector::parse("rust+go").unwrap()));
+        assert_eq!(
+            job.overrides,
+            [
+                ("jobs".to_owned(), "2".to_owned()),
+                ("timeout".to_owned(), "10".to_owned())
+            ]
+        );
+        assert_eq!(
+            job.list.simple_args(),
+            Some(vec!["git".to_owned(), "pull".to_owned()])
+        );
+        assert!(!job.is_plain());
 
-fn arg<'a, I>(line: &'a str, bytes: &mut Peekable<I>) -> Result<Option<&'a str>, &'static str>
-where
-    I: Iterator<Item = (usize, u8)>,
-{
-    let mut start = None;
-    let mut end = None;
-
-    // Skip over any leading whitespace
-    while let Some(&(_, b)) = bytes.peek() {
-        match b {
-            b' ' | b'\t' => {
-                bytes.next();
-            }
-            _ => break,
-        }
+        // Prefixes end at the first other word
+        let plain = parse("echo !x @y :z=1").unwrap();
+        assert!(plain.is_plain());
+        assert_eq!(plain.list.simple_args().unwrap().len(), 4);
     }
 
-    while let Some(&(i, b)) = bytes.peek() {
-        if start.is_none() {
-            start = Some(i)
-        }
-        match b {
-            // Evaluate a quoted string but do not return it
-            // We pass in i, the index of a quote, but start a character later. This ensures
-            // the production rules will produce strings with the quotes intact
-            b'"' => {
-                bytes.next();
-                double_quoted(line, bytes, i)?;
-            }
-            b'\'' => {
-                bytes.next();
-                single_quoted(line, bytes, i)?;
-            }
-            // If we see a backslash, assume that it is leading up to an escaped character
-            // and skip the next character
-            b'\\' => {
-                bytes.next();
-                bytes.next();
-            }
-            // If we see a byte from the following set, we've definitely reached the end of
-            // the argument
-            b' ' | b'\t' => {
-                end = Some(i);
-                break;
-            }
-            // By default just pop the next byte: it will be part of the argument
-            _ => {
-                bytes.next();
-            }
-        }
+    #[test]
+    fn jobs_reject_bad_prefixes() {
+        assert_eq!(parse("!x").unwrap_err(), "missing command after prefixes");
+        assert_eq!(
+            parse("@a @b ls").unwrap_err(),
+            "only one @SELECTOR may be given per command"
+        );
+        assert!(parse("@a, ls").is_err());
+        assert_eq!(parse("@{} ls").unwrap_err(), "empty @{DIR,...} target set");
     }
 
-    match (start, end) {
-        (Some(i), Some(j)) if i < j => Ok(Some(&line[i..j])),
-        (Some(i), None) => Ok(Some(&line[i..])),
-        _ => Ok(None),
+    fn pipeline(line: &str) -> Result<Pipeline, String> {
+        parse_pipeline(words::split(line)?.into_iter())
     }
-}
 
-fn double_quoted<'a, I>(
-    line: &'a str,
-    bytes: &mut Peekable<I>,
-    start: usize,
-) -> Result<&'a str, &'static str>
-where
-    I: Iterator<Item = (usize, u8)>,
-{
-    while let Some(&(i, b)) = bytes.peek() {
-        bytes.next();
-
-        if b == b'"' {
-            // We return an inclusive range to keep the quote type intact
-            return Ok(&line[start..=i]);
-        } else if b == b'\\' {
-            // Skip the next character even if it's a quote,
-            bytes.next();
-        }
+    #[test]
+    fn pipelines_split_at_each_pipe() {
+        let commands = pipeline("ls -l | grep x 2>&1 | wc -l > count")
+            .unwrap()
+            .commands;
+        assert_eq!(commands.len(), 3);
+        assert_eq!(commands[1].redirects, [Redirect::ErrorToOutput]);
+        assert_eq!(
+            commands[2].redirects,
+            [Redirect::Output {
+                path: "count".to_owned(),
+                append: false
+            }]
+        );
     }
 
-    Err("Unterminated double quote")
-}
+    #[test]
+    fn pipelines_need_a_command_on_each_side() {
+        assert_eq!(pipeline("ls |").unwrap_err(), "missing command after |");
+        assert_eq!(pipeline("| wc").unwrap_err(), "missing command before |");
+        assert_eq!(
+            pipeline("ls | | wc").unwrap_err(),
+            "missing command before |"
+        );
+        assert_eq!(pipeline("ls >").unwrap_err(), "missing file after >");
+        assert_eq!(pipeline("ls > | wc").unwrap_err(), "missing file after >");
+    }
 
-fn single_quoted<'a, I>(
-    line: &'a str,
-    bytes: &mut Peekable<I>,
-    start: usize,
-) -> Result<&'a str, &'static str>
-where
-    I: Iterator<Item = (usize, u8)>,
-{
-    while let Some(&(i, b)) = bytes.peek() {
-        bytes.next();
+    fn list(line: &str) -> Result<CommandList, String> {
+        parse_list(words::split(line)?.into_iter())
+    }
 
-        if b == b'\'' {
-            // We return an inclusive range to keep the quote type intact
-            return Ok(&line[start..=i]);
-        };
+    #[test]
+    fn lists_split_at_each_connector() {
+        let parsed = list("make && make test || echo failed; echo done;").unwrap();
+        let connectors: Vec<_> = parsed.rest.iter().map(|(c, _)| *c).collect();
+        assert_eq!(connectors, [Connector::And, Connector::Or, Connector::Then]);
+        assert_eq!(parsed.pipelines().count(), 4);
     }
 
-    Err("Unterminated single quote")
+    #[test]
+    fn lists_need_a_command_on_each_side() {
+        assert_eq!(list("make &&").unwrap_err(), "missing command after &&");
+        assert_eq!(list("make ||").unwrap_err(), "missing command after ||");
+        assert_eq!(list("&& make").unwrap_err(), "missing command before &&");
+        assert_eq!(list("make; ; ls").unwrap_err(), "missing command before ;");
+        assert_eq!(list(";").unwrap_err(), "missing command before ;");
+        assert_eq!(
+            list("make && | wc").unwrap_err(),
+            "missing command before |"
+        );
+        assert_eq!(list("").unwrap_err(), "missing command");
+    }
 }
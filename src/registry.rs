@@ -0,0 +1,199 @@
+#![warn(clippy::pedantic)]
+#![warn(clippy::nursery)]
+#![warn(clippy::cargo)]
+#![allow(clippy::default_trait_access)]
+#![allow(clippy::multiple_crate_versions)]
+#![allow(clippy::redundant_pub_crate)]
+#![allow(clippy::uninlined_format_args)]
+#![allow(clippy::unnecessary_semicolon)]
+
+//! Reading and writing registry files.
+//!
+//! Two formats are accepted. The structured format is TOML, with one `[[dir]]` table per
+//! directory:
+//!
+//! ```toml
+//! # Relative paths are resolved against the directory holding this file
+//! [[dir]]
+//! path = "services/api"
+//! name = "api"
+//! tags = ["rust", "web"]
+//! groups = ["backend"]
+//! env = { RUST_LOG = "debug" }
+//! ```
+//!
+//! Any file without a `[` table header is read in the original format: a whitespace
+//! separated list of directories, where `#` starts a comment.
+
+use serde::{Deserialize, Serialize};
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::context::get_home_dir;
+
+/// A single directory read from a registry file, with the line it was found on.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Hash)]
+pub(crate) struct RegistryEntry {
+    pub line: usize,
+    pub path: PathBuf,
+    pub name: Option<String>,
+    pub tags: Vec<String>,
+    pub groups: Vec<String>,
+    pub env: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RegistryFile {
+    #[serde(default)]
+    dir: Vec<FileEntry>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileEntry {
+    path: toml::Spanned<String>,
+    name: Option<String>,
+    #[serde(default)]
+    tags: Vec<String>,
+    #[serde(default)]
+    groups: Vec<String>,
+    #[serde(default)]
+    env: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Serialize)]
+struct RegistryFileOut<'a> {
+    dir: Vec<FileEntryOut<'a>>,
+}
+
+#[derive(Debug, Serialize)]
+struct FileEntryOut<'a> {
+    path: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<&'a str>,
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    tags: &'a [String],
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    groups: &'a [String],
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    env: &'a BTreeMap<String, String>,
+}
+
+/// Expands a leading `~` and resolves relative paths against `base`.
+pub(crate) fn resolve(base: &Path, raw: &str) -> PathBuf {
+    let expanded = match raw.strip_prefix('~') {
+        Some(rest) if rest.is_empty() || rest.starts_with('/') => {
+            format!("{}{}", get_home_dir(), rest)
+        }
+        _ => raw.to_owned(),
+    };
+
+    let path = PathBuf::from(expanded);
+    if path.is_absolute() {
+        path
+    } else {
+        base.join(path)
+    }
+}
+
+fn line_of(contents: &str, offset: usize) -> usize {
+    contents[..offset].matches('\n').count() + 1
+}
+
+fn is_structured(contents: &str) -> bool {
+    contents.lines().any(|l| l.trim_start().starts_with('['))
+}
+
+fn parse_structured(contents: &str, base: &Path) -> Result<Vec<RegistryEntry>, String> {
+    let file: RegistryFile = toml::from_str(contents).map_err(|e| e.to_string())?;
+
+    Ok(file
+        .dir
+        .into_iter()
+        .map(|entry| RegistryEntry {
+            line: line_of(contents, entry.path.start()),
+            path: resolve(base, entry.path.get_ref()),
+            name: entry.name,
+            tags: entry.tags,
+            groups: entry.groups,
+            env: entry.env,
+        })
+        .collect())
+}
+
+fn parse_plain(contents: &str, base: &Path) -> Vec<RegistryEntry> {
+    let mut entries = Vec::new();
+    for (i, line) in contents.lines().enumerate() {
+        let line_content = line.split('#').next().unwrap_or_default();
+        for word in line_content.split_whitespace() {
+            entries.push(RegistryEntry {
+                line: i + 1,
+                path: resolve(base, word),
+                ..RegistryEntry::default()
+            });
+        }
+    }
+    entries
+}
+
+pub(crate) fn read_registry_file(name: &str) -> Result<Vec<RegistryEntry>, String> {
+    debug!("Searching for registry file: {}", name);
+    let contents = fs::read_to_string(name).map_err(|e| format!("{}: {}", name, e))?;
+    trace!("File read, raw contents: {}", &contents);
+
+    let base = Path::new(name)
+        .parent()
+        .map_or_else(PathBuf::new, Path::to_path_buf);
+
+    if is_structured(&contents) {
+        debug!("Reading {} as a structured registry file", name);
+        parse_structured(&contents, &base).map_err(|e| format!("{}: {}", name, e))
+    } else {
+        debug!("Reading {} as a plain registry file", name);
+        Ok(parse_plain(&contents, &base))
+    }
+}
+
+/// Writes `entries` to `name` in the structured format.
+pub(crate) fn write_registry_file(name: &str, entries: &[RegistryEntry]) -> Result<(), String> {
+    debug!("Writing registry file: {}", name);
+    let file = RegistryFileOut {
+        dir: entries
+            .iter()
+            .map(|entry| FileEntryOut {
+                path: entry.path.to_string_lossy().into_owned(),
+                name: entry.name.as_deref(),
+                tags: &entry.tags,
+                groups: &entry.groups,
+                env: &entry.env,
+            })
+            .collect(),
+    };
+
+    let contents = toml::to_string(&file).map_err(|e| e.to_string())?;
+    fs::write(name, contents).map_err(|e| format!("{}: {}", name, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plain_comments_run_to_the_end_of_the_line() {
+        let contents = "# repositories\napi web # web/old\n\n  /srv/db#backup\n";
+        assert!(!is_structured(contents));
+
+        let entries = parse_plain(contents, Path::new("/work"));
+        let found: Vec<_> = entries.iter().map(|e| (e.line, e.path.as_path())).collect();
+        assert_eq!(
+            found,
+            [
+                (2, Path::new("/work/api")),
+                (2, Path::new("/work/web")),
+                (4, Path::new("/srv/db")),
+            ]
+        );
+    }
+}
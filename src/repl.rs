@@ -3,8 +3,11 @@
 #![warn(clippy::cargo)]
 #![allow(clippy::default_trait_access)]
 #![allow(clippy::multiple_crate_versions)]
+#![allow(clippy::redundant_pub_crate)]
+#![allow(clippy::uninlined_format_args)]
+#![allow(clippy::unnecessary_semicolon)]
 
-use colored::*;
+use colored::Colorize;
 
 use rustyline::completion::{Completer, FilenameCompleter, Pair};
 use rustyline::config::{Configurer, OutputStreamType};
@@ -17,12 +20,17 @@ use rustyline::{
 
 use std::borrow::{Cow, Cow::Borrowed, Cow::Owned, ToOwned};
 use std::env;
-use std::io::{Error as IOError, ErrorKind, Result as IOResult};
+use std::fs;
+use std::io::{self, prelude::*, Error as IOError, ErrorKind, Result as IOResult};
 use std::path::PathBuf;
 use std::string::ToString;
 
-use crate::context::{self, Context, MshConfig};
+use crate::config;
+use crate::context::{self, Context, MshConfig, Predicate};
+use crate::discover::Discovery;
+use crate::exec::{self, Job};
 use crate::parser;
+use crate::state;
 
 struct MshHelper(FilenameCompleter, MatchingBracketHighlighter, HistoryHinter);
 
@@ -78,23 +86,84 @@ impl Default for MshHelper {
 #[derive(Debug, Clone, PartialEq, Hash)]
 pub(crate) enum Action {
     Loop,
-    Dump,
+    Dump(Option<String>),
     Buffer(String),
-    Register(Vec<String>),
-    Unregister(Vec<String>),
-    RegisterFile(String),
-    ClearRegistry(Vec<String>),
+    Register {
+        group: Option<String>,
+        dirs: Vec<String>,
+    },
+    Unregister {
+        group: Option<String>,
+        dirs: Vec<String>,
+    },
+    RegisterFile {
+        group: Option<String>,
+        file: String,
+    },
+    Discover {
+        group: Option<String>,
+        roots: Vec<String>,
+        markers: Vec<String>,
+        depth: Option<usize>,
+        ignore: Vec<String>,
+    },
+    RegisterWorkspace {
+        group: Option<String>,
+        path: String,
+        tag: bool,
+    },
+    ClearRegistry {
+        group: Option<String>,
+        dirs: Vec<String>,
+    },
+    Filter {
+        group: Option<String>,
+        predicate: Predicate,
+        invert: bool,
+        dry_run: bool,
+    },
+    CreateGroups(Vec<String>),
+    DeleteGroup(String),
+    UseGroup(String),
+    ListGroups,
+    Tag {
+        tag: String,
+        dirs: Vec<String>,
+    },
+    Untag {
+        tag: String,
+        dirs: Vec<String>,
+    },
+    ListTags,
+    SaveRegistry {
+        group: Option<String>,
+        file: String,
+    },
     ChDir(String),
-    StoreEnv { name: String, value: String },
-    RemoveEnv { name: String },
-    Execute(Vec<String>),
+    StoreEnv {
+        name: String,
+        value: String,
+    },
+    RemoveEnv {
+        name: String,
+    },
+    Set {
+        name: String,
+        value: String,
+    },
+    ShowSettings,
+    ShowJobs,
+    Execute(Job),
+    /// Exit the shell, with the exit code given to the builtin
     Exit(Option<String>),
+    /// A line which could not be parsed
+    Error(String),
 }
 
 fn find_history_file() -> Option<PathBuf> {
-    dirs::data_local_dir().and_then(|mut d| {
+    dirs::data_local_dir().map(|mut d| {
         d.push("msh-history");
-        Some(d)
+        d
     })
 }
 
@@ -103,20 +172,34 @@ pub fn get_cwd() -> IOResult<String> {
     debug!("current dir: {}", cwd);
     let home = context::get_home_dir();
     let len = home.len();
-    if cwd.starts_with(&home) {
+    if cwd.starts_with(home) {
         Ok(format!("~{}", &cwd[len..]))
     } else {
         Ok(cwd)
     }
 }
 
-fn get_prompt(ctx: &Context) -> IOResult<String> {
+#[allow(clippy::literal_string_with_formatting_args)]
+fn get_prompt(ctx: &Context, format: Option<&str>) -> IOResult<String> {
     if ctx.has_buffer() {
         Ok("... ".to_owned())
+    } else if let Some(format) = format {
+        let prompt = format
+            .replace("{group}", &ctx.active_group().blue().to_string())
+            .replace("{count}", &ctx.dir_count().to_string().blue().to_string())
+            .replace("{cwd}", &get_cwd()?.green().to_string());
+        trace!("prompt generated: {:?}", prompt);
+        Ok(prompt)
     } else {
         let mut prompt = String::with_capacity(40);
         let count = ctx.dir_count();
-        prompt.push_str(&format!("({}) ", count).blue().to_string());
+        let group = ctx.active_group();
+        let registry = if group == context::DEFAULT_GROUP {
+            format!("({}) ", count)
+        } else {
+            format!("({}:{}) ", group, count)
+        };
+        prompt.push_str(&registry.blue().to_string());
         prompt.push_str(&get_cwd()?.green().to_string());
         prompt.push_str("> ");
 
@@ -127,9 +210,6 @@ fn get_prompt(ctx: &Context) -> IOResult<String> {
 
 fn handle_loop_error(err: ReadlineError) {
     match err {
-        ReadlineError::Interrupted => {
-            println!("CTRL-C");
-        }
         ReadlineError::Eof => {
             println!("CTRL-D");
         }
@@ -143,18 +223,38 @@ fn init_context(cfg: &MshConfig) -> Context {
     debug!("Initializing context");
     let mut ctx = Context::default();
 
-    trace!("Preloading registry");
-    context::register_paths(&mut ctx, cfg.dirs());
+    if cfg.fresh() {
+        info!("Starting with a fresh registry");
+    } else {
+        trace!("Restoring saved registry");
+        state::load(&mut ctx);
+    }
+
+    for (name, value) in cfg.settings() {
+        trace!("Applying setting from command line: {} = {}", name, value);
+        if let Err(e) = ctx.set(name, value) {
+            eprintln!("{}", e);
+        }
+    }
+
+    if let Some(file) = cfg.registry_file() {
+        trace!("Preloading registry");
+        context::register_file(&mut ctx, None, file);
+    }
 
     ctx
 }
 
-fn init_editor() -> Editor<MshHelper> {
+fn init_editor(cfg: &MshConfig) -> Editor<MshHelper> {
     debug!("Init Editor");
+    let edit_mode = match cfg.edit_mode() {
+        config::EditMode::Emacs => EditMode::Emacs,
+        config::EditMode::Vi => EditMode::Vi,
+    };
     let config = Config::builder()
         .history_ignore_space(true)
         .completion_type(CompletionType::List)
-        .edit_mode(EditMode::Emacs)
+        .edit_mode(edit_mode)
         .output_stream(OutputStreamType::Stdout)
         .build();
     let helper = MshHelper::default();
@@ -173,6 +273,7 @@ fn init_editor() -> Editor<MshHelper> {
     rl
 }
 
+#[allow(clippy::option_if_let_else)]
 fn load_history<H: Helper>(editor: &mut Editor<H>) -> IOResult<PathBuf> {
     if let Some(p) = find_history_file() {
         debug!("History file location: {}", p.display());
@@ -189,15 +290,161 @@ fn load_history<H: Helper>(editor: &mut Editor<H>) -> IOResult<PathBuf> {
     }
 }
 
-pub(crate) fn repl_loop(cfg: &MshConfig) -> Result<(), String> {
-    let mut rl = init_editor();
+/// What carrying out an action means for the shell.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Status {
+    /// Carry on, having finished with this exit status, or with none when a builtin
+    /// succeeded, so that the status of the last command is kept
+    Continue(Option<i32>),
+    /// Exit the shell with this code, or else the last exit status
+    Exit(Option<i32>),
+}
+
+fn report_err(result: Result<(), String>) -> Status {
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        return Status::Continue(Some(exec::EXIT_FAILURE));
+    }
+    Status::Continue(None)
+}
+
+/// Carries out a single action.
+#[allow(clippy::too_many_lines)]
+fn handle_action(ctx: &mut Context, action: Action) -> Status {
+    match action {
+        Action::Loop => {}
+        Action::Exit(code) => {
+            // The builtin has already checked that the code is a number
+            return Status::Exit(code.and_then(|c| c.parse().ok()));
+        }
+        Action::Error(e) => {
+            eprintln!("{}", e);
+            return Status::Continue(Some(exec::EXIT_USAGE));
+        }
+        Action::Buffer(s) => ctx.push_buffer(&s),
+        Action::Register { group, dirs } => {
+            context::register_paths(ctx, group.as_deref(), &dirs);
+        }
+        Action::Unregister { group, dirs } => {
+            context::unregister_paths(ctx, group.as_deref(), &dirs);
+        }
+        Action::RegisterWorkspace { group, path, tag } => {
+            return report_err(context::register_workspace(
+                ctx,
+                group.as_deref(),
+                &path,
+                tag,
+            ));
+        }
+        Action::ClearRegistry { group, dirs } => {
+            if let Err(e) = ctx.clear_registry(group.as_deref()) {
+                return report_err(Err(e));
+            }
+            context::register_paths(ctx, group.as_deref(), &dirs);
+        }
+        Action::RegisterFile { group, file } => {
+            context::register_file(ctx, group.as_deref(), &file);
+        }
+        Action::Discover {
+            group,
+            roots,
+            markers,
+            depth,
+            ignore,
+        } => match Discovery::new(markers, depth, &ignore) {
+            Ok(discovery) => context::discover_paths(ctx, group.as_deref(), &roots, &discovery),
+            Err(e) => eprintln!("{}", e),
+        },
+        Action::Filter {
+            group,
+            predicate,
+            invert,
+            dry_run,
+        } => {
+            return report_err(context::filter_registry(
+                ctx,
+                group.as_deref(),
+                &predicate,
+                invert,
+                dry_run,
+            ))
+        }
+        Action::CreateGroups(names) => {
+            for name in names {
+                match ctx.create_group(&name) {
+                    Ok(true) => println!("Created group: {}", name),
+                    Ok(false) => println!("Group already exists: {}", name),
+                    Err(e) => eprintln!("Cannot create group: {}", e),
+                };
+            }
+        }
+        Action::DeleteGroup(name) => return report_err(ctx.delete_group(&name)),
+        Action::UseGroup(name) => return report_err(ctx.use_group(&name)),
+        Action::ListGroups => print!("{}", ctx.describe_groups()),
+        Action::Tag { tag, dirs } => context::tag_paths(ctx, &tag, &dirs),
+        Action::Untag { tag, dirs } => context::untag_paths(ctx, &tag, &dirs),
+        Action::ListTags => print!("{}", ctx.describe_tags()),
+        Action::SaveRegistry { group, file } => {
+            return report_err(context::save_registry_file(ctx, group.as_deref(), &file));
+        }
+        Action::Execute(job) => match ctx.run_executable(&job) {
+            Ok(report) => {
+                println!("{}", report);
+                return Status::Continue(Some(report.exit_code()));
+            }
+            Err(e) => {
+                eprintln!("{}", e);
+                return Status::Continue(Some(exec::EXIT_USAGE));
+            }
+        },
+        Action::ChDir(p) => {
+            env::set_current_dir(p).unwrap_or_else(|e| {
+                println!("ChDir error: {}", e);
+            });
+        }
+        Action::StoreEnv { name, value } => env::set_var(name, value),
+        Action::RemoveEnv { name } => env::remove_var(name),
+        Action::Set { name, value } => return report_err(ctx.set(&name, &value)),
+        Action::ShowSettings => print!("{}", ctx.settings()),
+        Action::ShowJobs => println!("jobs = {}", ctx.settings().jobs),
+        Action::Dump(group) => match ctx.describe(group.as_deref()) {
+            Ok(s) => println!("{}", s),
+            Err(e) => eprintln!("{}", e),
+        },
+    };
+    Status::Continue(None)
+}
+
+/// Parses and carries out one line, updating `last` with its exit status unless it was a
+/// builtin which succeeded. Returns the exit code once the shell should exit.
+fn run_line(ctx: &mut Context, line: &str, last: &mut i32) -> Option<i32> {
+    let action = parser::handle_line(ctx, line);
+    match handle_action(ctx, action) {
+        Status::Continue(code) => {
+            if let Some(code) = code {
+                *last = code;
+            }
+            None
+        }
+        Status::Exit(code) => Some(code.unwrap_or(*last)),
+    }
+}
+
+/// Runs the interactive shell, returning the exit code to leave with.
+pub(crate) fn repl_loop(cfg: &MshConfig) -> Result<i32, String> {
+    let mut rl = init_editor(cfg);
     let hist_path = load_history(&mut rl);
     let mut ctx = init_context(cfg);
+    exec::install_interrupt_handler()?;
+    if !cfg.norc() {
+        run_rc_file(&mut ctx);
+    }
 
     info!("Starting REPL");
 
-    loop {
-        let prompt = match get_prompt(&ctx) {
+    let mut last = exec::EXIT_SUCCESS;
+    let exit_code = loop {
+        let prompt = match get_prompt(&ctx, cfg.prompt()) {
             Ok(string) => string,
             Err(io_err) => {
                 return Err(format!("{}", io_err));
@@ -206,59 +453,154 @@ pub(crate) fn repl_loop(cfg: &MshConfig) -> Result<(), String> {
         match rl.readline(&prompt) {
             Ok(line) => {
                 // rl.add_history_entry(line.as_str());
-                match parser::handle_line(&mut ctx, &line) {
-                    Action::Loop => {}
-                    Action::Exit(opt_s) => {
-                        if let Some(s) = opt_s {
-                            println!("{}", s);
-                        }
-                        break;
-                    }
-                    Action::Buffer(s) => ctx.push_buffer(&s),
-                    Action::Register(v) => context::register_paths(&mut ctx, &v),
-                    Action::Unregister(v) => context::unregister_paths(&mut ctx, &v),
-                    Action::ClearRegistry(v) => {
-                        ctx.clear_registry();
-                        context::register_paths(&mut ctx, &v);
-                    }
-                    Action::RegisterFile(s) => {
-                        match context::read_registry_file(&s) {
-                            Ok(v) => {
-                                context::register_paths(&mut ctx, &v);
-                            }
-                            Err(e) => {
-                                eprintln!("{}", e);
-                            }
-                        };
-                    }
-                    Action::Execute(v) => ctx.run_executable(&v),
-                    Action::ChDir(p) => {
-                        env::set_current_dir(p).unwrap_or_else(|e| {
-                            println!("ChDir error: {}", e);
-                        });
-                    }
-                    Action::StoreEnv { name, value } => env::set_var(name, value),
-                    Action::RemoveEnv { name } => env::remove_var(name),
-                    Action::Dump => {
-                        println!("{}", &ctx);
-                    }
-                };
+                if let Some(code) = run_line(&mut ctx, &line, &mut last) {
+                    break code;
+                }
+                exec::clear_interrupts();
+            }
+            Err(ReadlineError::Interrupted) => {
+                // Like bash, Ctrl-C at the prompt only abandons the current line
+                trace!("Line interrupted, discarding buffer");
+                ctx.take_buffer("");
             }
             Err(e) => {
                 handle_loop_error(e);
-                break;
+                break last;
             }
         }
-    }
+    };
 
     if let Ok(path) = hist_path {
         match rl.save_history(&path) {
-            Ok(_) => info!("Saving history file: {}", &path.display()),
+            Ok(()) => info!("Saving history file: {}", &path.display()),
             Err(e) => warn!("Cannot save history file: {} error: {}", &path.display(), e),
         };
     } else {
         warn!("No history file to save to, skipping save procedure");
     };
 
-    Ok(())
+    if !cfg.fresh() {
+        state::save(&ctx);
+    }
+
+    Ok(exit_code)
+}
+
+/// Whether a line of a script or rc file is a comment, including a `#!` line.
+fn is_comment(line: &str) -> bool {
+    line.trim_start().starts_with('#')
+}
+
+/// Runs the builtin commands in `~/.mshrc`, if it exists. An `exit` stops the rc file, but
+/// not the shell.
+fn run_rc_file(ctx: &mut Context) {
+    let Some(path) = config::find_rc_file() else {
+        warn!("Could not determine rc file location");
+        return;
+    };
+    let contents = match fs::read_to_string(&path) {
+        Ok(c) => c,
+        Err(e) => {
+            info!("No rc file run: {}", e);
+            return;
+        }
+    };
+
+    debug!("Running rc file: {}", path.display());
+    let mut last = exec::EXIT_SUCCESS;
+    for line in contents.lines().filter(|l| !is_comment(l)) {
+        if run_line(ctx, line, &mut last).is_some() || exec::interrupted() {
+            break;
+        }
+    }
+    ctx.take_buffer("");
+    exec::clear_interrupts();
+}
+
+/// The lines to run without a REPL: from `-c`, a script file, or standard input.
+fn script_lines(cfg: &MshConfig) -> Result<Vec<String>, String> {
+    if let Some(command) = cfg.command() {
+        return Ok(command.lines().map(ToOwned::to_owned).collect());
+    }
+    if let Some(script) = cfg.script() {
+        let contents = fs::read_to_string(script).map_err(|e| format!("{}: {}", script, e))?;
+        return Ok(contents.lines().map(ToOwned::to_owned).collect());
+    }
+    io::stdin()
+        .lock()
+        .lines()
+        .collect::<IOResult<_>>()
+        .map_err(|e| format!("Cannot read standard input: {}", e))
+}
+
+/// Runs commands without a REPL, returning the exit code: that of the last command, unless
+/// `exit` gave one. Like other shells, an interrupted command stops the whole script.
+pub(crate) fn run_script(cfg: &MshConfig) -> Result<i32, String> {
+    let lines = script_lines(cfg)?;
+    let mut ctx = init_context(cfg);
+    exec::install_interrupt_handler()?;
+
+    info!("Running {} lines non-interactively", lines.len());
+    let mut last = exec::EXIT_SUCCESS;
+    for line in lines {
+        if is_comment(&line) {
+            continue;
+        }
+        if let Some(code) = run_line(&mut ctx, &line, &mut last) {
+            return Ok(code);
+        }
+        // Ctrl-C stops the script whether it came during a fan-out or a builtin
+        if exec::interrupted() {
+            debug!("Interrupted, stopping the script");
+            return Ok(exec::EXIT_INTERRUPTED);
+        }
+    }
+    if ctx.has_buffer() {
+        eprintln!("msh: unexpected end of input after a continued line");
+        return Ok(exec::EXIT_USAGE);
+    }
+
+    Ok(last)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builtins_keep_the_last_exit_status() {
+        let mut ctx = Context::default();
+        let mut last = exec::EXIT_FAILURE;
+        assert_eq!(run_line(&mut ctx, "set jobs 2", &mut last), None);
+        assert_eq!(last, exec::EXIT_FAILURE);
+        assert_eq!(run_line(&mut ctx, "", &mut last), None);
+        assert_eq!(last, exec::EXIT_FAILURE);
+    }
+
+    #[test]
+    fn failed_builtins_set_the_exit_status() {
+        let mut ctx = Context::default();
+        let mut last = exec::EXIT_SUCCESS;
+        assert_eq!(run_line(&mut ctx, "set jobs none", &mut last), None);
+        assert_eq!(last, exec::EXIT_FAILURE);
+    }
+
+    #[test]
+    fn parse_errors_exit_with_usage() {
+        let mut ctx = Context::default();
+        let mut last = exec::EXIT_SUCCESS;
+        assert_eq!(run_line(&mut ctx, "echo 'unterminated", &mut last), None);
+        assert_eq!(last, exec::EXIT_USAGE);
+    }
+
+    #[test]
+    fn exit_uses_its_code_or_the_last_status() {
+        let mut ctx = Context::default();
+        let mut last = exec::EXIT_TIMEOUT;
+        assert_eq!(run_line(&mut ctx, "exit 7", &mut last), Some(7));
+        assert_eq!(
+            run_line(&mut ctx, "exit", &mut last),
+            Some(exec::EXIT_TIMEOUT)
+        );
+    }
 }
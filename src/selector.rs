@@ -0,0 +1,128 @@
+#![warn(clippy::pedantic)]
+#![warn(clippy::nursery)]
+#![warn(clippy::cargo)]
+#![allow(clippy::default_trait_access)]
+#![allow(clippy::multiple_crate_versions)]
+#![allow(clippy::redundant_pub_crate)]
+#![allow(clippy::uninlined_format_args)]
+#![allow(clippy::unnecessary_semicolon)]
+
+/// Characters with a meaning inside a target selector, which names may not contain.
+const RESERVED: &[char] = &[',', '+', '!', '@', '{', '}', ' ', '\t'];
+
+/// A boolean expression over group, tag or directory names, choosing which registered
+/// directories a command runs in. `,` binds tighter than `+`, so `a,b+c` selects
+/// `(a and b) or c`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) enum Selector {
+    /// Directories in the group of this name, carrying this tag, or given this name
+    Name(String),
+    Not(Box<Self>),
+    All(Vec<Self>),
+    Any(Vec<Self>),
+}
+
+impl Selector {
+    /// Evaluates the selector for one directory, given a test for whether that directory is
+    /// in the group or carries the tag of a given name.
+    pub fn matches<F>(&self, has: &F) -> bool
+    where
+        F: Fn(&str) -> bool,
+    {
+        match self {
+            Self::Name(name) => has(name),
+            Self::Not(inner) => !inner.matches(has),
+            Self::All(terms) => terms.iter().all(|t| t.matches(has)),
+            Self::Any(terms) => terms.iter().any(|t| t.matches(has)),
+        }
+    }
+
+    /// Every group, tag or directory name the selector refers to.
+    pub fn names(&self) -> Vec<&str> {
+        match self {
+            Self::Name(name) => vec![name.as_str()],
+            Self::Not(inner) => inner.names(),
+            Self::All(terms) | Self::Any(terms) => terms.iter().flat_map(Self::names).collect(),
+        }
+    }
+}
+
+/// Checks that `name` can be used as a group, tag or directory name within a selector.
+pub(crate) fn validate_name(name: &str) -> Result<(), String> {
+    if name.is_empty() {
+        return Err("empty name in selector".to_owned());
+    }
+    if name.contains(RESERVED) {
+        return Err(format!(
+            "invalid name \"{}\": names may not contain any of , + ! @ {{ }} or whitespace",
+            name
+        ));
+    }
+    Ok(())
+}
+
+fn parse_term(term: &str) -> Result<Selector, String> {
+    if let Some(inner) = term.strip_prefix('!') {
+        return Ok(Selector::Not(Box::new(parse_term(inner)?)));
+    }
+    validate_name(term)?;
+    Ok(Selector::Name(term.to_owned()))
+}
+
+fn parse_all(expr: &str) -> Result<Selector, String> {
+    let mut terms: Vec<Selector> = expr.split(',').map(parse_term).collect::<Result<_, _>>()?;
+    if terms.len() == 1 {
+        return Ok(terms.remove(0));
+    }
+    Ok(Selector::All(terms))
+}
+
+/// Parses the expression following the `@` of a target selector.
+pub(crate) fn parse(expr: &str) -> Result<Selector, String> {
+    trace!("Parsing selector: {}", expr);
+    let mut alternatives: Vec<Selector> =
+        expr.split('+').map(parse_all).collect::<Result<_, _>>()?;
+    if alternatives.len() == 1 {
+        return Ok(alternatives.remove(0));
+    }
+    Ok(Selector::Any(alternatives))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn name(n: &str) -> Selector {
+        Selector::Name(n.to_owned())
+    }
+
+    #[test]
+    fn all_binds_tighter_than_any() {
+        assert_eq!(
+            parse("a,b+c").unwrap(),
+            Selector::Any(vec![Selector::All(vec![name("a"), name("b")]), name("c")])
+        );
+        assert_eq!(
+            parse("a+!b,c").unwrap(),
+            Selector::Any(vec![
+                name("a"),
+                Selector::All(vec![Selector::Not(Box::new(name("b"))), name("c")]),
+            ])
+        );
+    }
+
+    #[test]
+    fn matches_by_precedence() {
+        let selector = parse("a,b+c").unwrap();
+        assert!(selector.matches(&|n| n == "c"));
+        assert!(selector.matches(&|n| n == "a" || n == "b"));
+        assert!(!selector.matches(&|n| n == "a"));
+    }
+
+    #[test]
+    fn rejects_empty_names() {
+        assert!(parse("a,").is_err());
+        assert!(parse("+b").is_err());
+        assert!(parse("!").is_err());
+    }
+}
@@ -0,0 +1,437 @@
+#![warn(clippy::pedantic)]
+#![warn(clippy::nursery)]
+#![warn(clippy::cargo)]
+#![allow(clippy::default_trait_access)]
+#![allow(clippy::multiple_crate_versions)]
+#![allow(clippy::redundant_pub_crate)]
+#![allow(clippy::uninlined_format_args)]
+#![allow(clippy::unnecessary_semicolon)]
+
+use std::fmt::{Display, Error as FmtError, Formatter};
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Controls how the stderr of each child is presented.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) enum StderrMode {
+    /// Mixed with stdout, in the order lines arrived
+    #[default]
+    Interleave,
+    /// Printed as its own block after stdout
+    Separate,
+    /// Discarded
+    Hide,
+}
+
+impl FromStr for StderrMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "interleave" => Ok(Self::Interleave),
+            "separate" => Ok(Self::Separate),
+            "hide" => Ok(Self::Hide),
+            _ => Err(format!(
+                "Invalid stderr mode \"{}\", expected one of: interleave, separate, hide",
+                s
+            )),
+        }
+    }
+}
+
+impl Display for StderrMode {
+    fn fmt(&self, formatter: &mut Formatter) -> Result<(), FmtError> {
+        let name = match self {
+            Self::Interleave => "interleave",
+            Self::Separate => "separate",
+            Self::Hide => "hide",
+        };
+        write!(formatter, "{}", name)
+    }
+}
+
+/// Controls when and how the output of each child is printed.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) enum OutputMode {
+    /// One block per directory, printed once the child has exited
+    #[default]
+    Grouped,
+    /// Each line printed as soon as it arrives, prefixed with a directory label
+    Stream,
+    /// Nothing printed but the summary
+    Quiet,
+}
+
+impl FromStr for OutputMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "grouped" => Ok(Self::Grouped),
+            "stream" => Ok(Self::Stream),
+            "quiet" => Ok(Self::Quiet),
+            _ => Err(format!(
+                "Invalid output mode \"{}\", expected one of: grouped, stream, quiet",
+                s
+            )),
+        }
+    }
+}
+
+impl Display for OutputMode {
+    fn fmt(&self, formatter: &mut Formatter) -> Result<(), FmtError> {
+        let name = match self {
+            Self::Grouped => "grouped",
+            Self::Stream => "stream",
+            Self::Quiet => "quiet",
+        };
+        write!(formatter, "{}", name)
+    }
+}
+
+/// Controls the order in which registered directories are listed and run.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) enum Order {
+    /// The order the directories were registered in
+    #[default]
+    Insertion,
+    /// Sorted by path
+    Path,
+}
+
+impl FromStr for Order {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "insertion" => Ok(Self::Insertion),
+            "path" => Ok(Self::Path),
+            _ => Err(format!(
+                "Invalid order \"{}\", expected one of: insertion, path",
+                s
+            )),
+        }
+    }
+}
+
+impl Display for Order {
+    fn fmt(&self, formatter: &mut Formatter) -> Result<(), FmtError> {
+        let name = match self {
+            Self::Insertion => "insertion",
+            Self::Path => "path",
+        };
+        write!(formatter, "{}", name)
+    }
+}
+
+/// What becomes of a glob pattern which matches no files in a directory.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) enum NoMatch {
+    /// Passed on unchanged, as POSIX shells do
+    #[default]
+    Literal,
+    /// Removed, like bash's nullglob
+    Null,
+    /// The command fails in that directory, like bash's failglob
+    Fail,
+}
+
+impl FromStr for NoMatch {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "literal" => Ok(Self::Literal),
+            "null" => Ok(Self::Null),
+            "fail" => Ok(Self::Fail),
+            _ => Err(format!(
+                "Invalid nomatch mode \"{}\", expected one of: literal, null, fail",
+                s
+            )),
+        }
+    }
+}
+
+impl Display for NoMatch {
+    fn fmt(&self, formatter: &mut Formatter) -> Result<(), FmtError> {
+        let name = match self {
+            Self::Literal => "literal",
+            Self::Null => "null",
+            Self::Fail => "fail",
+        };
+        write!(formatter, "{}", name)
+    }
+}
+
+/// The maximum number of children run at once; zero lets the thread pool decide.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) struct Jobs(pub usize);
+
+impl FromStr for Jobs {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "auto" {
+            return Ok(Self(0));
+        }
+        s.parse()
+            .map(Self)
+            .map_err(|_| format!("Invalid job count \"{}\", expected a number or \"auto\"", s))
+    }
+}
+
+impl Display for Jobs {
+    fn fmt(&self, formatter: &mut Formatter) -> Result<(), FmtError> {
+        match self.0 {
+            0 => write!(formatter, "auto"),
+            n => write!(formatter, "{}", n),
+        }
+    }
+}
+
+fn parse_seconds(s: &str) -> Result<Duration, String> {
+    s.parse::<f64>()
+        .ok()
+        .filter(|secs| secs.is_finite() && *secs >= 0.0)
+        .map(Duration::from_secs_f64)
+        .ok_or_else(|| format!("Invalid duration \"{}\", expected a number of seconds", s))
+}
+
+/// How long a child may run before it is terminated; `None` waits forever.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) struct Timeout(pub Option<Duration>);
+
+impl FromStr for Timeout {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "none" {
+            return Ok(Self(None));
+        }
+        let duration = parse_seconds(s)?;
+        Ok(Self(
+            Some(duration).filter(|d| *d != Duration::from_secs(0)),
+        ))
+    }
+}
+
+impl Display for Timeout {
+    fn fmt(&self, formatter: &mut Formatter) -> Result<(), FmtError> {
+        match self.0 {
+            None => write!(formatter, "none"),
+            Some(d) => write!(formatter, "{}", d.as_secs_f64()),
+        }
+    }
+}
+
+/// How long a timed out child has to exit after SIGTERM before it is sent SIGKILL.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) struct Grace(pub Duration);
+
+impl Default for Grace {
+    fn default() -> Self {
+        Self(Duration::from_secs(5))
+    }
+}
+
+impl FromStr for Grace {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parse_seconds(s).map(Self)
+    }
+}
+
+impl Display for Grace {
+    fn fmt(&self, formatter: &mut Formatter) -> Result<(), FmtError> {
+        write!(formatter, "{}", self.0.as_secs_f64())
+    }
+}
+
+/// An option which is either on or off.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) struct Switch(pub bool);
+
+impl FromStr for Switch {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "on" | "true" => Ok(Self(true)),
+            "off" | "false" => Ok(Self(false)),
+            _ => Err(format!("Invalid switch \"{}\", expected on or off", s)),
+        }
+    }
+}
+
+impl Display for Switch {
+    fn fmt(&self, formatter: &mut Formatter) -> Result<(), FmtError> {
+        write!(formatter, "{}", if self.0 { "on" } else { "off" })
+    }
+}
+
+/// Help text describing every setting, shown by `set --help`.
+pub(crate) const HELP: &str = "SETTINGS:
+    grace     Seconds a timed out child gets to exit after SIGTERM before SIGKILL
+    jobs      Children run at once: a number, or auto; 1 runs serially in registry order
+    nomatch   What a glob pattern matching no files becomes: literal, passed on as is; null,
+              removed; or fail, failing the command in that directory
+    order     Order directories are listed and run in: insertion or path
+    output    How child output is printed: grouped, stream or quiet
+    pipefail  Whether a pipeline fails when any of its commands fails, not just the last: on
+              or off
+    stderr    How child stderr is shown: interleave, separate or hide
+    timeout   Seconds a child may run before it is terminated, or none
+
+Any setting can be overridden for a single command by prefixing it with :NAME=VALUE,
+for example `:output=stream cargo test`.";
+
+/// Shell-wide options, changed with the `set` builtin.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Hash)]
+pub(crate) struct Settings {
+    pub grace: Grace,
+    pub jobs: Jobs,
+    pub nomatch: NoMatch,
+    pub order: Order,
+    pub output: OutputMode,
+    pub pipefail: Switch,
+    pub stderr: StderrMode,
+    pub timeout: Timeout,
+}
+
+impl Settings {
+    pub fn set(&mut self, name: &str, value: &str) -> Result<(), String> {
+        debug!("Setting {} to {}", name, value);
+        match name {
+            "grace" => self.grace = value.parse()?,
+            "jobs" => self.jobs = value.parse()?,
+            "nomatch" => self.nomatch = value.parse()?,
+            "order" => self.order = value.parse()?,
+            "output" => self.output = value.parse()?,
+            "pipefail" => self.pipefail = value.parse()?,
+            "stderr" => self.stderr = value.parse()?,
+            "timeout" => self.timeout = value.parse()?,
+            _ => return Err(format!("Unknown setting: {}", name)),
+        };
+        Ok(())
+    }
+
+    /// Returns a copy of these settings with each `(name, value)` pair applied in order.
+    pub fn with_overrides(&self, overrides: &[(String, String)]) -> Result<Self, String> {
+        let mut settings = self.clone();
+        for (name, value) in overrides {
+            settings.set(name, value)?;
+        }
+        Ok(settings)
+    }
+}
+
+impl Display for Settings {
+    fn fmt(&self, formatter: &mut Formatter) -> Result<(), FmtError> {
+        writeln!(formatter, "grace = {}", self.grace)?;
+        writeln!(formatter, "jobs = {}", self.jobs)?;
+        writeln!(formatter, "nomatch = {}", self.nomatch)?;
+        writeln!(formatter, "order = {}", self.order)?;
+        writeln!(formatter, "output = {}", self.output)?;
+        writeln!(formatter, "pipefail = {}", self.pipefail)?;
+        writeln!(formatter, "stderr = {}", self.stderr)?;
+        writeln!(formatter, "timeout = {}", self.timeout)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn overrides(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(name, value)| ((*name).to_owned(), (*value).to_owned()))
+            .collect()
+    }
+
+    #[test]
+    fn output_modes() {
+        assert_eq!("grouped".parse(), Ok(OutputMode::Grouped));
+        assert_eq!("stream".parse(), Ok(OutputMode::Stream));
+        assert_eq!("quiet".parse(), Ok(OutputMode::Quiet));
+        assert!("Stream".parse::<OutputMode>().is_err());
+        assert_eq!(OutputMode::Stream.to_string(), "stream");
+    }
+
+    #[test]
+    fn overrides_apply_to_a_copy() {
+        let settings = Settings::default();
+        let overridden = settings
+            .with_overrides(&overrides(&[("output", "stream"), ("stderr", "hide")]))
+            .unwrap();
+        assert_eq!(overridden.output, OutputMode::Stream);
+        assert_eq!(overridden.stderr, StderrMode::Hide);
+        assert_eq!(settings, Settings::default());
+
+        // A later override of the same setting wins
+        let overridden = settings
+            .with_overrides(&overrides(&[("output", "stream"), ("output", "quiet")]))
+            .unwrap();
+        assert_eq!(overridden.output, OutputMode::Quiet);
+    }
+
+    #[test]
+    fn overrides_reject_unknown_settings_and_values() {
+        let settings = Settings::default();
+        assert!(settings
+            .with_overrides(&overrides(&[("colour", "on")]))
+            .is_err());
+        assert!(settings
+            .with_overrides(&overrides(&[("output", "loud")]))
+            .is_err());
+    }
+
+    #[test]
+    fn jobs_are_a_count_or_auto() {
+        assert_eq!("4".parse(), Ok(Jobs(4)));
+        assert_eq!("auto".parse(), Ok(Jobs(0)));
+        // Zero also leaves the thread pool to decide
+        assert_eq!("0".parse::<Jobs>().unwrap().to_string(), "auto");
+        assert_eq!(Jobs(1).to_string(), "1");
+        for invalid in ["", "-1", "two", "1.5"] {
+            assert!(
+                invalid.parse::<Jobs>().is_err(),
+                "{:?} was accepted",
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn timeouts_are_seconds_or_none() {
+        assert_eq!(
+            "1.5".parse(),
+            Ok(Timeout(Some(Duration::from_millis(1500))))
+        );
+        assert_eq!("none".parse(), Ok(Timeout(None)));
+        assert_eq!("0".parse(), Ok(Timeout(None)));
+        assert_eq!(Timeout(Some(Duration::from_secs(90))).to_string(), "90");
+        for invalid in ["", "-1", "ten", "inf", "NaN"] {
+            assert!(
+                invalid.parse::<Timeout>().is_err(),
+                "{:?} was accepted",
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn grace_is_seconds() {
+        assert_eq!(Grace::default(), Grace(Duration::from_secs(5)));
+        assert_eq!("0".parse(), Ok(Grace(Duration::from_secs(0))));
+        assert_eq!("0.25".parse(), Ok(Grace(Duration::from_millis(250))));
+        for invalid in ["none", "-0.5", ""] {
+            assert!(
+                invalid.parse::<Grace>().is_err(),
+                "{:?} was accepted",
+                invalid
+            );
+        }
+    }
+}
@@ -0,0 +1,84 @@
+#![warn(clippy::pedantic)]
+#![warn(clippy::nursery)]
+#![warn(clippy::cargo)]
+#![allow(clippy::default_trait_access)]
+#![allow(clippy::multiple_crate_versions)]
+#![allow(clippy::redundant_pub_crate)]
+#![allow(clippy::uninlined_format_args)]
+#![allow(clippy::unnecessary_semicolon)]
+
+use serde::{Deserialize, Serialize};
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+
+use crate::context::Context;
+
+/// The parts of a context that outlive a session: its groups, their directories, and the
+/// tags, names and environment given to those directories.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct State {
+    pub active_group: String,
+    #[serde(default)]
+    pub groups: BTreeMap<String, Vec<PathBuf>>,
+    #[serde(default)]
+    pub tags: BTreeMap<String, Vec<PathBuf>>,
+    #[serde(default)]
+    pub names: BTreeMap<String, PathBuf>,
+    #[serde(default)]
+    pub env: BTreeMap<String, BTreeMap<String, String>>,
+}
+
+fn find_state_file() -> Option<PathBuf> {
+    dirs::data_local_dir().map(|mut d| {
+        d.push("msh-state.toml");
+        d
+    })
+}
+
+/// Restores the registry saved by the previous session, if there is one.
+pub(crate) fn load(ctx: &mut Context) {
+    let Some(path) = find_state_file() else {
+        warn!("Could not determine state file location");
+        return;
+    };
+
+    debug!("State file location: {}", path.display());
+    let contents = match fs::read_to_string(&path) {
+        Ok(c) => c,
+        Err(e) => {
+            info!("No saved state loaded: {}", e);
+            return;
+        }
+    };
+
+    match toml::from_str(&contents) {
+        Ok(state) => {
+            ctx.restore(state);
+            info!("State file loaded");
+        }
+        Err(e) => eprintln!("Cannot read saved registry {}: {}", path.display(), e),
+    };
+}
+
+/// Saves the registry so that the next session can restore it.
+pub(crate) fn save(ctx: &Context) {
+    let Some(path) = find_state_file() else {
+        warn!("No state file to save to, skipping save procedure");
+        return;
+    };
+
+    let result = toml::to_string(&ctx.snapshot())
+        .map_err(|e| e.to_string())
+        .and_then(|s| {
+            if let Some(dir) = path.parent() {
+                fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+            }
+            fs::write(&path, s).map_err(|e| e.to_string())
+        });
+    match result {
+        Ok(()) => info!("Saving state file: {}", path.display()),
+        Err(e) => warn!("Cannot save state file: {} error: {}", path.display(), e),
+    };
+}
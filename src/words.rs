@@ -0,0 +1,545 @@
+#![warn(clippy::pedantic)]
+#![warn(clippy::nursery)]
+#![warn(clippy::cargo)]
+#![allow(clippy::default_trait_access)]
+#![allow(clippy::multiple_crate_versions)]
+#![allow(clippy::redundant_pub_crate)]
+#![allow(clippy::uninlined_format_args)]
+#![allow(clippy::unnecessary_semicolon)]
+
+//! Splitting a line into words, the way a POSIX shell does.
+//!
+//! Words are separated by unquoted whitespace. Within a word:
+//!
+//! - single quotes preserve everything up to the closing quote
+//! - double quotes preserve everything but `$VAR` expansion and `$(...)` substitution, and
+//!   a backslash only escapes `$`, `"`, `\` and `` ` ``
+//! - outside quotes, a backslash preserves the next character, `$VAR` and `${VAR}` expand
+//!   to the variable, and `~` at the start of a word expands to the home directory
+//!
+//! A `$(...)` command substitution is kept as written, as it is only run later in each
+//! directory. Its output is then split into fields at whitespace unless it was quoted.
+//!
+//! Unquoted operators end a word, with or without surrounding whitespace: `;`, `&&` and `||`
+//! separate the pipelines of a command list, `|` separates the commands of a pipeline, and
+//! `<`, `>`, `>>`, `2>`, `2>>` and `2>&1` redirect a command's input and output. Quotes are
+//! removed, but each word remembers which of its parts were quoted, so that only unquoted
+//! glob patterns are later expanded in each directory.
+
+use glob::{MatchOptions, Pattern};
+
+use std::borrow::Cow;
+use std::iter::Peekable;
+use std::mem;
+use std::path::Path;
+use std::str::Chars;
+
+use crate::context::get_home_dir;
+use crate::discover;
+
+/// A run of characters in a word, which either was or was not quoted, or a command
+/// substitution yet to be run.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) enum Segment {
+    Unquoted(String),
+    Quoted(String),
+    /// The command between `$(` and `)`, and whether it was within double quotes
+    Substitution {
+        source: String,
+        quoted: bool,
+    },
+}
+
+impl Segment {
+    fn text(&self) -> Cow<'_, str> {
+        match self {
+            Self::Unquoted(t) | Self::Quoted(t) => Cow::Borrowed(t),
+            Self::Substitution { source, .. } => Cow::Owned(format!("$({})", source)),
+        }
+    }
+}
+
+/// A single word of a command line, after expansion and quote removal.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Hash)]
+pub(crate) struct Word {
+    segments: Vec<Segment>,
+}
+
+impl Word {
+    /// A word matched literally, as if it had been quoted.
+    pub fn literal(text: String) -> Self {
+        Self {
+            segments: vec![Segment::Quoted(text)],
+        }
+    }
+
+    /// The text of the word, with quotes removed and any command substitutions as written.
+    pub fn text(&self) -> String {
+        self.segments.iter().map(Segment::text).collect()
+    }
+
+    /// The command of each substitution in the word.
+    pub fn substitutions(&self) -> impl Iterator<Item = &str> {
+        self.segments.iter().filter_map(|s| match s {
+            Segment::Substitution { source, .. } => Some(source.as_str()),
+            _ => None,
+        })
+    }
+
+    /// Replaces each command substitution with the output `run` gives for its command.
+    /// Unquoted output is split into fields at whitespace, which may split the word into
+    /// several, or leave none at all.
+    pub fn substitute<E, F>(&self, run: &mut F) -> Result<Vec<Self>, E>
+    where
+        F: FnMut(&str) -> Result<String, E>,
+    {
+        let mut words = Vec::new();
+        let mut current: Option<Self> = None;
+        for segment in &self.segments {
+            match segment {
+                Segment::Unquoted(t) => current.get_or_insert_with(Self::default).push_unquoted(t),
+                Segment::Quoted(t) => current.get_or_insert_with(Self::default).push_quoted(t),
+                Segment::Substitution {
+                    source,
+                    quoted: true,
+                } => {
+                    let output = run(source)?;
+                    current
+                        .get_or_insert_with(Self::default)
+                        .push_quoted(&output);
+                }
+                Segment::Substitution {
+                    source,
+                    quoted: false,
+                } => {
+                    let output = run(source)?;
+                    if output.starts_with(char::is_whitespace) {
+                        words.extend(current.take());
+                    }
+                    for (i, field) in output.split_whitespace().enumerate() {
+                        if i > 0 {
+                            words.extend(current.take());
+                        }
+                        current
+                            .get_or_insert_with(Self::default)
+                            .push_unquoted(field);
+                    }
+                    if output.ends_with(char::is_whitespace) {
+                        words.extend(current.take());
+                    }
+                }
+            }
+        }
+        words.extend(current);
+        Ok(words)
+    }
+
+    /// The paths relative to `dir` matched by the word as a glob pattern, in order, or
+    /// `None` if it is not a pattern. Only unquoted `*`, `?` and `[` are special, and they
+    /// never match a `/` or a leading `.`. An invalid pattern is not a pattern.
+    pub fn glob(&self, dir: &Path) -> Option<Vec<String>> {
+        let is_pattern = self
+            .segments
+            .iter()
+            .any(|s| matches!(s, Segment::Unquoted(t) if discover::is_pattern(t)));
+        if !is_pattern {
+            return None;
+        }
+
+        let pattern: String = self
+            .segments
+            .iter()
+            .map(|s| match s {
+                Segment::Unquoted(t) => t.clone(),
+                other => Pattern::escape(&other.text()),
+            })
+            .collect();
+        let absolute = pattern.starts_with('/');
+        let dot_slash = pattern.starts_with("./");
+        let full = if absolute {
+            pattern
+        } else {
+            format!("{}/{}", Pattern::escape(&dir.to_string_lossy()), pattern)
+        };
+        let strict = MatchOptions {
+            case_sensitive: true,
+            require_literal_separator: true,
+            require_literal_leading_dot: true,
+        };
+        // Walking strictly would skip hidden files even for patterns naming them, such as
+        // `.env*`, so the walk lets them through and each match is then checked strictly
+        let walk = MatchOptions {
+            require_literal_leading_dot: false,
+            ..strict
+        };
+        let compiled = Pattern::new(&full).ok()?;
+
+        let paths = glob::glob_with(&full, walk).ok()?;
+        let matches = paths
+            .filter_map(Result::ok)
+            .filter(|path| {
+                let text = path.to_string_lossy();
+                let special = text.ends_with("/.") || text.ends_with("/..");
+                !special && compiled.matches_path_with(path, strict)
+            })
+            .map(|path| {
+                if absolute {
+                    return path.to_string_lossy().into_owned();
+                }
+                // Stripping the directory also drops a leading ./, which shells keep
+                let relative = path.strip_prefix(dir).unwrap_or(&path).to_string_lossy();
+                if dot_slash {
+                    format!("./{}", relative)
+                } else {
+                    relative.into_owned()
+                }
+            })
+            .collect();
+        Some(matches)
+    }
+
+    fn push_unquoted(&mut self, text: &str) {
+        if let Some(Segment::Unquoted(last)) = self.segments.last_mut() {
+            last.push_str(text);
+        } else {
+            self.segments.push(Segment::Unquoted(text.to_owned()));
+        }
+    }
+
+    fn push_quoted(&mut self, text: &str) {
+        if let Some(Segment::Quoted(last)) = self.segments.last_mut() {
+            last.push_str(text);
+        } else {
+            self.segments.push(Segment::Quoted(text.to_owned()));
+        }
+    }
+
+    fn push_substitution(&mut self, source: String, quoted: bool) {
+        self.segments.push(Segment::Substitution { source, quoted });
+    }
+}
+
+/// A control or redirection operator, only recognised outside quotes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) enum Operator {
+    /// `|`, feeding the output of one command into the next
+    Pipe,
+    /// `;`, running one pipeline after another
+    Sequence,
+    /// `&&`, running the next pipeline only if the last one succeeded
+    And,
+    /// `||`, running the next pipeline only if the last one failed
+    Or,
+    /// `<`
+    Input,
+    /// `>`
+    Output,
+    /// `>>`
+    Append,
+    /// `2>`
+    Error,
+    /// `2>>`
+    ErrorAppend,
+    /// `2>&1`
+    ErrorToOutput,
+}
+
+impl Operator {
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::Pipe => "|",
+            Self::Sequence => ";",
+            Self::And => "&&",
+            Self::Or => "||",
+            Self::Input => "<",
+            Self::Output => ">",
+            Self::Append => ">>",
+            Self::Error => "2>",
+            Self::ErrorAppend => "2>>",
+            Self::ErrorToOutput => "2>&1",
+        }
+    }
+}
+
+/// Every operator, with those that start with another operator listed before it.
+const OPERATORS: &[Operator] = &[
+    Operator::ErrorToOutput,
+    Operator::ErrorAppend,
+    Operator::Error,
+    Operator::Append,
+    Operator::Output,
+    Operator::Input,
+    Operator::Or,
+    Operator::Pipe,
+    Operator::Sequence,
+    Operator::And,
+];
+
+/// A word, or an operator between words.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) enum Token {
+    Word(Word),
+    Operator(Operator),
+}
+
+/// Whether `c` ends an unquoted word.
+const fn is_boundary(c: char) -> bool {
+    matches!(c, ' ' | '\t' | '|' | '<' | '>' | ';' | '&')
+}
+
+/// Reads an operator, if one starts at the next character.
+fn operator(chars: &mut Peekable<Chars>) -> Option<Operator> {
+    OPERATORS.iter().copied().find(|op| {
+        let mut ahead = chars.clone();
+        if op.as_str().chars().all(|c| ahead.next() == Some(c)) {
+            *chars = ahead;
+            true
+        } else {
+            false
+        }
+    })
+}
+
+/// Expands the variable following a `$`, leaving unset variables as they were written.
+fn variable(chars: &mut Peekable<Chars>) -> Result<String, &'static str> {
+    let braced = chars.next_if_eq(&'{').is_some();
+
+    let mut name = String::new();
+    while let Some(c) = chars.next_if(|&c| c.is_ascii_alphanumeric() || c == '_') {
+        name.push(c);
+    }
+    if braced && chars.next_if_eq(&'}').is_none() {
+        return Err("Unterminated ${ in variable expansion");
+    }
+    if name.is_empty() {
+        return Ok(if braced { "${}" } else { "$" }.to_owned());
+    }
+
+    let expansion = std::env::var(&name).unwrap_or_else(|_| {
+        if braced {
+            format!("${{{}}}", name)
+        } else {
+            format!("${}", name)
+        }
+    });
+    debug!("Expanded ${} to {}", name, expansion);
+    Ok(expansion)
+}
+
+fn single_quoted(chars: &mut Peekable<Chars>) -> Result<String, &'static str> {
+    let mut text = String::new();
+    for c in chars.by_ref() {
+        if c == '\'' {
+            return Ok(text);
+        }
+        text.push(c);
+    }
+    Err("Unterminated single quote")
+}
+
+/// Reads the command of a `$(...)` substitution up to its closing parenthesis, which may
+/// hold quotes and parentheses of its own.
+fn substitution(chars: &mut Peekable<Chars>) -> Result<String, &'static str> {
+    let mut source = String::new();
+    let mut depth = 0_usize;
+    let mut quote = None;
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (None, ')') if depth == 0 => return Ok(source),
+            (None, ')') => depth -= 1,
+            (None, '(') => depth += 1,
+            (None, '\'' | '"') => quote = Some(c),
+            (Some(q), _) if c == q => quote = None,
+            (None | Some('"'), '\\') => {
+                source.push(c);
+                if let Some(e) = chars.next() {
+                    source.push(e);
+                }
+                continue;
+            }
+            _ => {}
+        }
+        source.push(c);
+    }
+    Err("Unterminated $( in command substitution")
+}
+
+fn double_quoted(chars: &mut Peekable<Chars>, word: &mut Word) -> Result<(), &'static str> {
+    let mut text = String::new();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => {
+                word.push_quoted(&text);
+                return Ok(());
+            }
+            '\\' => match chars.next() {
+                Some(e @ ('$' | '"' | '\\' | '`')) => text.push(e),
+                Some(e) => {
+                    text.push('\\');
+                    text.push(e);
+                }
+                None => break,
+            },
+            '$' if chars.next_if_eq(&'(').is_some() => {
+                word.push_quoted(&mem::take(&mut text));
+                word.push_substitution(substitution(chars)?, true);
+            }
+            '$' => text.push_str(&variable(chars)?),
+            _ => text.push(c),
+        }
+    }
+    Err("Unterminated double quote")
+}
+
+/// Reads one word, stopping at unquoted whitespace or an operator.
+fn word(chars: &mut Peekable<Chars>) -> Result<Word, &'static str> {
+    let mut word = Word::default();
+
+    if chars.next_if_eq(&'~').is_some() {
+        match chars.peek() {
+            Some(&c) if c != '/' && !is_boundary(c) => word.push_unquoted("~"),
+            _ => word.push_unquoted(get_home_dir()),
+        }
+    }
+
+    while let Some(c) = chars.next_if(|&c| !is_boundary(c)) {
+        match c {
+            '\'' => word.push_quoted(&single_quoted(chars)?),
+            '"' => double_quoted(chars, &mut word)?,
+            '\\' => {
+                if let Some(e) = chars.next() {
+                    word.push_quoted(e.encode_utf8(&mut [0; 4]));
+                }
+            }
+            '$' if chars.next_if_eq(&'(').is_some() => {
+                word.push_substitution(substitution(chars)?, false);
+            }
+            '$' => word.push_unquoted(&variable(chars)?),
+            _ => word.push_unquoted(c.encode_utf8(&mut [0; 4])),
+        }
+    }
+    Ok(word)
+}
+
+/// Splits a line into words and operators, expanding variables and removing quotes.
+pub(crate) fn split(line: &str) -> Result<Vec<Token>, &'static str> {
+    let mut chars = line.chars().peekable();
+    let mut tokens = Vec::new();
+
+    loop {
+        while chars.next_if(|&c| c == ' ' || c == '\t').is_some() {}
+        if chars.peek().is_none() {
+            break;
+        }
+        match operator(&mut chars) {
+            Some(op) => tokens.push(Token::Operator(op)),
+            None if chars.peek() == Some(&'&') => {
+                return Err("Running commands in the background with & is not supported");
+            }
+            None => tokens.push(Token::Word(word(&mut chars)?)),
+        }
+    }
+
+    debug!("Split line into tokens: {:?}", tokens);
+    Ok(tokens)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The words of `line` as text, with each operator as written.
+    fn texts(line: &str) -> Vec<String> {
+        split(line)
+            .unwrap()
+            .iter()
+            .map(|token| match token {
+                Token::Word(word) => word.text(),
+                Token::Operator(op) => op.as_str().to_owned(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn single_quotes_expand_nothing() {
+        std::env::set_var("MSH_TEST_QUOTED", "value");
+        assert_eq!(
+            texts(r#"echo '$MSH_TEST_QUOTED \" a'"#),
+            ["echo", r#"$MSH_TEST_QUOTED \" a"#]
+        );
+        assert_eq!(texts(r#"echo "$MSH_TEST_QUOTED a""#), ["echo", "value a"]);
+    }
+
+    #[test]
+    fn backslashes() {
+        assert_eq!(texts(r"echo a\ b \$x \\"), ["echo", "a b", "$x", "\\"]);
+        // Within double quotes, only $ " \ and ` are escaped
+        assert_eq!(texts(r#"echo "\$x \" \\ \n""#), ["echo", r#"$x " \ \n"#]);
+    }
+
+    #[test]
+    fn tilde_only_expands_at_the_start_of_a_word() {
+        let home = get_home_dir();
+        assert_eq!(
+            texts("ls ~ ~/src a~b ~user"),
+            ["ls", home, &format!("{}/src", home), "a~b", "~user"]
+        );
+    }
+
+    #[test]
+    fn unset_variables_are_left_as_written() {
+        std::env::remove_var("MSH_TEST_UNSET");
+        assert_eq!(
+            texts("echo $MSH_TEST_UNSET ${MSH_TEST_UNSET} $"),
+            ["echo", "$MSH_TEST_UNSET", "${MSH_TEST_UNSET}", "$"]
+        );
+        assert_eq!(
+            split("echo ${MSH_TEST_UNSET"),
+            Err("Unterminated ${ in variable expansion")
+        );
+    }
+
+    #[test]
+    fn substitutions_hold_parentheses_and_quotes() {
+        let tokens = split(r#"echo a$(echo "(x)" ')' $(pwd)) "$(date)""#).unwrap();
+        let [_, Token::Word(unquoted), Token::Word(quoted)] = &tokens[..] else {
+            panic!("expected three words, got {:?}", tokens);
+        };
+        assert_eq!(
+            unquoted.substitutions().collect::<Vec<_>>(),
+            [r#"echo "(x)" ')' $(pwd)"#]
+        );
+        assert_eq!(quoted.substitutions().collect::<Vec<_>>(), ["date"]);
+
+        // Only unquoted output is split into fields
+        let mut run = |_: &str| Ok::<_, ()>("x  y".to_owned());
+        let texts = |words: Vec<Word>| words.iter().map(Word::text).collect::<Vec<_>>();
+        assert_eq!(texts(unquoted.substitute(&mut run).unwrap()), ["ax", "y"]);
+        assert_eq!(texts(quoted.substitute(&mut run).unwrap()), ["x  y"]);
+
+        assert_eq!(
+            split("echo $(echo ')'"),
+            Err("Unterminated $( in command substitution")
+        );
+    }
+
+    #[test]
+    fn operators_are_read_longest_first() {
+        assert_eq!(texts("make 2>&1 | less"), ["make", "2>&1", "|", "less"]);
+        assert_eq!(
+            texts("make 2>err.log 2>>all.log"),
+            ["make", "2>", "err.log", "2>>", "all.log"]
+        );
+        assert_eq!(
+            texts("a && b || c; d"),
+            ["a", "&&", "b", "||", "c", ";", "d"]
+        );
+    }
+
+    #[test]
+    fn background_jobs_are_rejected() {
+        let error = Err("Running commands in the background with & is not supported");
+        assert_eq!(split("sleep 1 &"), error);
+        assert_eq!(split("sleep 1 & echo"), error);
+    }
+}
@@ -0,0 +1,141 @@
+#![warn(clippy::pedantic)]
+#![warn(clippy::nursery)]
+#![warn(clippy::cargo)]
+#![allow(clippy::default_trait_access)]
+#![allow(clippy::multiple_crate_versions)]
+#![allow(clippy::redundant_pub_crate)]
+#![allow(clippy::uninlined_format_args)]
+#![allow(clippy::unnecessary_semicolon)]
+
+use glob::Pattern;
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::exec;
+
+/// The members of a Cargo or npm workspace.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Workspace {
+    /// The name of the directory holding the workspace manifest
+    pub name: String,
+    pub members: Vec<PathBuf>,
+}
+
+/// Expands member globs relative to `root`, keeping only directories holding `manifest`
+/// and not matched by any of the `exclude` globs.
+fn expand_members(
+    root: &Path,
+    manifest: &str,
+    include: &[String],
+    exclude: &[String],
+) -> Result<Vec<PathBuf>, String> {
+    let escaped_root = Pattern::escape(&root.to_string_lossy());
+    let exclude = exclude
+        .iter()
+        .map(|p| Pattern::new(&format!("{}/{}", escaped_root, p.trim_end_matches('/'))))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("invalid exclude pattern: {}", e))?;
+
+    let mut members = Vec::new();
+    for member in include {
+        let pattern = format!("{}/{}", escaped_root, member.trim_end_matches('/'));
+        let paths =
+            glob::glob(&pattern).map_err(|e| format!("invalid member {}: {}", member, e))?;
+        for path in paths.filter_map(Result::ok) {
+            if exec::interrupted() {
+                return Err("interrupted".to_owned());
+            }
+            let excluded = exclude.iter().any(|p| p.matches_path(&path));
+            if !excluded && path.join(manifest).is_file() && !members.contains(&path) {
+                members.push(path);
+            }
+        }
+    }
+    Ok(members)
+}
+
+fn string_list(value: Option<&toml::Value>) -> Vec<String> {
+    value
+        .and_then(toml::Value::as_array)
+        .map(|a| {
+            a.iter()
+                .filter_map(|v| v.as_str().map(ToOwned::to_owned))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Reads the members of a Cargo workspace, including the root package if there is one.
+fn read_cargo(root: &Path) -> Result<Option<Vec<PathBuf>>, String> {
+    let manifest = root.join("Cargo.toml");
+    let Ok(contents) = fs::read_to_string(&manifest) else {
+        return Ok(None);
+    };
+    let doc: toml::Value = contents
+        .parse()
+        .map_err(|e| format!("{}: {}", manifest.display(), e))?;
+    let Some(workspace) = doc.get("workspace") else {
+        return Ok(None);
+    };
+
+    let mut members = Vec::new();
+    if doc.get("package").is_some() {
+        members.push(root.to_owned());
+    }
+    let include = string_list(workspace.get("members"));
+    let exclude = string_list(workspace.get("exclude"));
+    for member in expand_members(root, "Cargo.toml", &include, &exclude)? {
+        if !members.contains(&member) {
+            members.push(member);
+        }
+    }
+    Ok(Some(members))
+}
+
+/// Reads the members of an npm or yarn workspace. Patterns starting with `!` exclude.
+fn read_npm(root: &Path) -> Result<Option<Vec<PathBuf>>, String> {
+    let manifest = root.join("package.json");
+    let Ok(contents) = fs::read_to_string(&manifest) else {
+        return Ok(None);
+    };
+    let doc: serde_json::Value =
+        serde_json::from_str(&contents).map_err(|e| format!("{}: {}", manifest.display(), e))?;
+
+    // Yarn also allows `"workspaces": { "packages": [...] }`
+    let patterns = match doc.get("workspaces") {
+        Some(serde_json::Value::Object(o)) => o.get("packages"),
+        other => other,
+    };
+    let Some(patterns) = patterns.and_then(serde_json::Value::as_array) else {
+        return Ok(None);
+    };
+
+    let (exclude, include): (Vec<String>, Vec<String>) = patterns
+        .iter()
+        .filter_map(|v| v.as_str().map(ToOwned::to_owned))
+        .partition(|p| p.starts_with('!'));
+    let exclude: Vec<String> = exclude.iter().map(|p| p[1..].to_owned()).collect();
+
+    expand_members(root, "package.json", &include, &exclude).map(Some)
+}
+
+/// Reads the workspace at `root`, trying `Cargo.toml` before `package.json`.
+pub(crate) fn read_workspace(root: &Path) -> Result<Workspace, String> {
+    let root = root.canonicalize().map_err(|e| e.to_string())?;
+    let name = root
+        .file_name()
+        .map_or_else(|| root.to_string_lossy(), |n| n.to_string_lossy())
+        .into_owned();
+
+    let members = match read_cargo(&root)? {
+        Some(members) => members,
+        None => read_npm(&root)?.ok_or_else(|| {
+            format!(
+                "no Cargo or npm workspace is declared in {}",
+                root.display()
+            )
+        })?,
+    };
+    Ok(Workspace { name, members })
+}
@@ -0,0 +1,113 @@
+#![warn(clippy::pedantic)]
+#![warn(clippy::nursery)]
+#![warn(clippy::cargo)]
+#![allow(clippy::multiple_crate_versions)]
+
+//! Runs the msh binary non-interactively, checking its exit status.
+
+use std::env;
+use std::fs;
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Command, Output, Stdio};
+
+/// An empty directory holding a registered directory `a`, which is also used as the home
+/// directory so that no user configuration is read.
+fn workdir(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("msh-cli-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(dir.join("a")).unwrap();
+    dir
+}
+
+fn msh(dir: &PathBuf) -> Command {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_msh"));
+    command
+        .current_dir(dir)
+        .env("HOME", dir)
+        .env("XDG_CONFIG_HOME", dir)
+        .env("XDG_DATA_HOME", dir)
+        .arg("--fresh");
+    command
+}
+
+fn run_command(name: &str, lines: &str) -> Output {
+    let dir = workdir(name);
+    msh(&dir).arg("-c").arg(lines).output().unwrap()
+}
+
+#[test]
+fn command_status_is_that_of_the_last_fan_out() {
+    assert_eq!(run_command("ok", "register a\ntrue").status.code(), Some(0));
+    assert_eq!(
+        run_command("failed", "register a\nfalse\ndirs")
+            .status
+            .code(),
+        Some(1)
+    );
+    assert_eq!(
+        run_command("recovered", "register a\nfalse\ntrue")
+            .status
+            .code(),
+        Some(0)
+    );
+}
+
+#[test]
+fn command_status_has_documented_codes() {
+    assert_eq!(
+        run_command("usage", "register a\necho 'open").status.code(),
+        Some(2)
+    );
+    assert_eq!(
+        run_command("spawn", "register a\nmsh-no-such-command")
+            .status
+            .code(),
+        Some(127)
+    );
+    assert_eq!(
+        run_command("timeout", "register a\nset timeout 1\nsleep 5")
+            .status
+            .code(),
+        Some(124)
+    );
+    assert_eq!(
+        run_command("exit", "register a\nfalse\nexit 3")
+            .status
+            .code(),
+        Some(3)
+    );
+}
+
+#[test]
+fn script_file_status() {
+    let dir = workdir("script");
+    fs::write(dir.join("ok.msh"), "#!/usr/bin/env msh\nregister a\ntrue\n").unwrap();
+    fs::write(dir.join("failed.msh"), "register a\nfalse\nset jobs 2\n").unwrap();
+
+    let status = |script: &str| msh(&dir).arg(script).output().unwrap().status.code();
+    assert_eq!(status("ok.msh"), Some(0));
+    assert_eq!(status("failed.msh"), Some(1));
+    assert_eq!(status("missing.msh"), Some(1));
+}
+
+#[test]
+fn piped_stdin_status() {
+    let dir = workdir("stdin");
+    let status = |input: &str| {
+        let mut child = msh(&dir)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .spawn()
+            .unwrap();
+        child
+            .stdin
+            .take()
+            .unwrap()
+            .write_all(input.as_bytes())
+            .unwrap();
+        child.wait().unwrap().code()
+    };
+    assert_eq!(status("register a\ntrue\n"), Some(0));
+    assert_eq!(status("register a\ntrue\nfalse\n"), Some(1));
+}